@@ -1,51 +1,136 @@
-use tab_coordinator::TabManager;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sqlite_wrapper::{
+    ffi, Error, Migration, OpenOptions, Params, QueryOptions, ResultSet, RowMode, Value,
+    WorkerClient, WorkerRequest,
+};
+use std::rc::Rc;
+use tab_coordinator::{Election, HeartbeatOptions, LeaderChanges, TabManager, TabManagerOptions};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::JsFuture;
-use web_sys::Worker;
+
+mod transaction;
+
+pub use transaction::Transaction;
+
+/// Options for `new BrowserSQLite(options)`. In JS every field is optional:
+///
+/// ```js
+/// new BrowserSQLite({
+///     databaseName: "app.db",
+///     vfs: "opfs", // or "memory"
+///     opfsDirectory: ".opfs-sahpool",
+///     opfsCapacity: 6,
+///     sqliteWorkerUrl: "./pkg/sqlite_wrapper/sqlite_wrapper.js",
+///     coordinatorWorkerUrl: "/pkg/worker/tab_coordinator_shared_worker.js",
+///     election: "coordinator", // or "lock"
+///     heartbeatIntervalMs: 1000,
+///     heartbeatTimeoutMs: 5000,
+/// });
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Options {
+    /// Database file opened by the leader. Tabs coordinate per database.
+    pub database_name: String,
+    #[serde(flatten)]
+    pub open: OpenOptions,
+    /// Script of the dedicated SQLite worker.
+    pub sqlite_worker_url: String,
+    /// Script of the coordinating shared worker.
+    pub coordinator_worker_url: String,
+    /// How the leader is chosen; see [`Election`].
+    pub election: Election,
+    #[serde(flatten)]
+    pub heartbeat: HeartbeatOptions,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            database_name: "app.db".to_string(),
+            open: OpenOptions::default(),
+            sqlite_worker_url: "./pkg/sqlite_wrapper/sqlite_wrapper.js".to_string(),
+            coordinator_worker_url: TabManagerOptions::default().shared_worker_url,
+            election: Election::default(),
+            heartbeat: HeartbeatOptions::default(),
+        }
+    }
+}
+
+impl Options {
+    pub fn from_js(value: &JsValue) -> Result<Options, Error> {
+        if value.is_null() || value.is_undefined() {
+            return Ok(Options::default());
+        }
+        serde_wasm_bindgen::from_value(value.clone())
+            .map_err(|e| Error::new(ffi::SQLITE_MISUSE, format!("Invalid options: {}", e)))
+    }
+}
 
 #[wasm_bindgen]
 pub struct BrowserSQLite {
-    worker: Worker,
-    tab_manager: TabManager,
+    worker: WorkerClient,
+    tab_manager: Rc<TabManager>,
 }
 
 #[wasm_bindgen]
 impl BrowserSQLite {
+    /// Starts this tab's SQLite worker and joins the coordinator. `options`
+    /// is optional; see [`Options`].
     #[wasm_bindgen(constructor)]
-    pub fn new() -> Result<BrowserSQLite, JsValue> {
-        let worker = Worker::new("./pkg/sqlite_wrapper/sqlite_wrapper.js")?;
-        let tab_manager = TabManager::new(worker.clone())?;
+    pub fn new(options: JsValue) -> Result<BrowserSQLite, JsValue> {
+        let options = Options::from_js(&options)?;
+        let worker = WorkerClient::spawn(&options.sqlite_worker_url)?;
+
+        // Only the leader's worker ever opens the database, on first use
+        let configure = WorkerRequest::Configure {
+            filename: options.database_name.clone(),
+            options: options.open.clone(),
+        };
+        worker.notify(configure)?;
+
+        let tab_manager_options = TabManagerOptions {
+            shared_worker_url: options.coordinator_worker_url,
+            name: Some(options.database_name),
+            election: options.election,
+            heartbeat: options.heartbeat,
+        };
+        let tab_manager = Rc::new(TabManager::with_options(
+            worker.clone(),
+            &tab_manager_options,
+        )?);
         Ok(BrowserSQLite {
             worker,
             tab_manager,
         })
     }
 
-    pub async fn execute(&self, sql: &str) -> Result<(), JsValue> {
-        // Check if we're the leader first
-        let is_leader = self.tab_manager.check_leader().await?;
-        if !is_leader {
-            return Err(JsValue::from_str(
-                "Only leader can execute write operations",
-            ));
-        }
+    /// Executes a write. `params` is an optional array of positional or object
+    /// of named parameters, bound by SQLite rather than spliced into the SQL.
+    /// Resolves to a result carrying `rowsAffected` and `lastInsertRowid`.
+    /// Works from any tab; followers route the write to the leader.
+    pub async fn execute(&self, sql: &str, params: JsValue) -> Result<JsValue, JsValue> {
+        let request = WorkerRequest::Execute {
+            sql: sql.to_string(),
+            params: Params::from_js(&params)?,
+            txn: None,
+        };
 
-        let promise = js_sys::Promise::new(&mut |resolve, _reject| {
-            let handler = move |e: web_sys::MessageEvent| {
-                resolve.call1(&JsValue::NULL, &e.data()).unwrap();
-            };
-            let closure = Closure::once(handler);
-            self.worker
-                .set_onmessage(Some(closure.as_ref().unchecked_ref()));
-            self.worker.post_message(&JsValue::from_str(sql)).unwrap();
-            closure.forget();
-        });
-
-        JsFuture::from(promise).await?;
-        Ok(())
+        let result: ResultSet = send(&self.worker, &self.tab_manager, request).await?;
+        Ok(result.to_js(RowMode::Array))
     }
 
-    pub async fn query(&self, sql: &str) -> Result<JsValue, JsValue> {
+    /// Runs a query on the leader's connection, routing it through the
+    /// coordinator when this tab is not the leader. Resolves to an object with
+    /// `columns`, `declTypes` and `rows`; pass `{ rowMode: "object" }` as
+    /// `options` to get rows keyed by column name.
+    pub async fn query(
+        &self,
+        sql: &str,
+        params: JsValue,
+        options: JsValue,
+    ) -> Result<JsValue, JsValue> {
         let is_leader = self.tab_manager.check_leader().await?;
         web_sys::console::log_1(&JsValue::from_str(&format!(
             "BrowserSQLite: Is leader? {}",
@@ -54,25 +139,109 @@ impl BrowserSQLite {
 
         if is_leader {
             // We're the leader, execute query directly
-            let promise = js_sys::Promise::new(&mut |resolve, _reject| {
-                let handler = move |e: web_sys::MessageEvent| {
-                    resolve.call1(&JsValue::NULL, &e.data()).unwrap();
-                };
-                let closure = Closure::once(handler);
-                self.worker
-                    .set_onmessage(Some(closure.as_ref().unchecked_ref()));
-                self.worker
-                    .post_message(&JsValue::from_str(&format!("QUERY:{}", sql)))
-                    .unwrap();
-                closure.forget();
-            });
-
-            JsFuture::from(promise).await
+            let options = QueryOptions::from_js(&options)?;
+            let request = WorkerRequest::Query {
+                sql: sql.to_string(),
+                params: Params::from_js(&params)?,
+                txn: None,
+            };
+            let result: ResultSet = post_to_worker(&self.worker, request).await?;
+            Ok(result.to_js(options.row_mode))
         } else {
-            self.tab_manager.route_query(sql).await
+            self.tab_manager.route_query(sql, params, options).await
         }
     }
 
+    /// Runs every statement in `sql` in order and resolves to an array with
+    /// one result per statement. Like `execute`, it works from any tab. A
+    /// failure names the statement index and byte offset.
+    pub async fn execute_script(&self, sql: &str, options: JsValue) -> Result<JsValue, JsValue> {
+        let options = QueryOptions::from_js(&options)?;
+        let request = WorkerRequest::Script {
+            sql: sql.to_string(),
+            txn: None,
+        };
+
+        let results: Vec<ResultSet> = send(&self.worker, &self.tab_manager, request).await?;
+        Ok(results
+            .iter()
+            .map(|result| result.to_js(options.row_mode))
+            .collect::<js_sys::Array>()
+            .into())
+    }
+
+    /// Brings the schema up to date by applying every migration newer than
+    /// `PRAGMA user_version`, in one transaction, and resolves to the version
+    /// reached. `migrations` is an array of `{ version, sql }` objects in
+    /// increasing version order. Only the leader runs migrations; on other
+    /// tabs this resolves to `null`. Rejects on any tab if the database is
+    /// newer than the last migration, e.g. in a tab still running an older
+    /// bundle.
+    pub async fn migrate(&self, migrations: JsValue) -> Result<Option<i32>, JsValue> {
+        let migrations = Migration::list_from_js(&migrations)?;
+
+        let is_leader = self.tab_manager.check_leader().await?;
+        if !is_leader {
+            let latest = Migration::latest_version(&migrations)?;
+            Migration::check_known(self.schema_version().await?, latest)?;
+            return Ok(None);
+        }
+
+        let request = WorkerRequest::Migrate { migrations };
+        Ok(Some(post_to_worker(&self.worker, request).await?))
+    }
+
+    /// Resolves to the schema version stored in `PRAGMA user_version`.
+    pub async fn schema_version(&self) -> Result<i32, JsValue> {
+        let request = WorkerRequest::Query {
+            sql: "PRAGMA user_version".to_string(),
+            params: Params::None,
+            txn: None,
+        };
+        let result: ResultSet = send(&self.worker, &self.tab_manager, request).await?;
+        match result.rows.first().and_then(|row| row.first()) {
+            Some(Value::Integer(version)) => Ok(*version as i32),
+            _ => Ok(0),
+        }
+    }
+
+    /// Resolves to an image of the whole database as a `Uint8Array`, e.g. for
+    /// the user to download. Works from any tab.
+    pub async fn export(&self) -> Result<js_sys::Uint8Array, JsValue> {
+        let bytes: ByteBuf = send(&self.worker, &self.tab_manager, WorkerRequest::Export).await?;
+        Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+    }
+
+    /// Replaces the whole database with `bytes`, a `Uint8Array` or
+    /// `ArrayBuffer` holding a SQLite database image such as one from
+    /// `export`. Works from any tab.
+    pub async fn import(&self, bytes: JsValue) -> Result<(), JsValue> {
+        let bytes = if let Some(buffer) = bytes.dyn_ref::<js_sys::ArrayBuffer>() {
+            js_sys::Uint8Array::new(buffer).to_vec()
+        } else if let Some(array) = bytes.dyn_ref::<js_sys::Uint8Array>() {
+            array.to_vec()
+        } else {
+            return Err(Error::new(
+                ffi::SQLITE_MISMATCH,
+                "import takes a Uint8Array or an ArrayBuffer",
+            )
+            .into());
+        };
+
+        let request = WorkerRequest::Import { bytes };
+        let _: ResultSet = send(&self.worker, &self.tab_manager, request).await?;
+        Ok(())
+    }
+
+    /// Begins a transaction on the leader's connection and resolves to a
+    /// handle for running statements in it. Works from any tab; other
+    /// requests wait until the transaction is committed or rolled back, and
+    /// it is rolled back if this tab goes away or the handle is freed first,
+    /// or if it runs no statement for 30 seconds.
+    pub async fn transaction(&self) -> Result<Transaction, JsValue> {
+        Ok(Transaction::begin(self.worker.clone(), self.tab_manager.clone()).await?)
+    }
+
     pub fn get_tab_id(&self) -> String {
         self.tab_manager.get_tab_id()
     }
@@ -80,4 +249,54 @@ impl BrowserSQLite {
     pub async fn check_leader(&self) -> Result<bool, JsValue> {
         self.tab_manager.check_leader().await
     }
+
+    /// Calls `callback(isLeader, leaderId)` whenever leadership changes, e.g.
+    /// to start or stop leader-only background work. Replaces any earlier
+    /// callback; pass `null` to remove it.
+    pub fn on_leader_change(&self, callback: Option<js_sys::Function>) {
+        self.tab_manager.on_leader_change(callback);
+    }
+
+    /// A stream of leadership changes: `await changes.next()` resolves to the
+    /// next `{ isLeader, leaderId }`.
+    pub fn leader_changes(&self) -> LeaderChanges {
+        self.tab_manager.leader_changes()
+    }
+
+    /// Makes this tab the leader. Leadership changes are reported through
+    /// `on_leader_change`; a transaction open on the old leader is lost.
+    pub fn request_leadership(&self) -> Result<(), JsValue> {
+        self.tab_manager.request_leadership()
+    }
+
+    /// Hands leadership to another tab, if there is one.
+    pub fn yield_leadership(&self) -> Result<(), JsValue> {
+        self.tab_manager.yield_leadership()
+    }
+}
+
+/// Posts a request to this tab's SQLite worker and waits for its reply.
+async fn post_to_worker<T: DeserializeOwned>(
+    worker: &WorkerClient,
+    request: WorkerRequest,
+) -> Result<T, Error> {
+    worker.request(request).await.into_result()
+}
+
+/// Sends a request to the leader's SQLite worker: directly when this tab is
+/// the leader, otherwise through the coordinator.
+async fn send<T: DeserializeOwned>(
+    worker: &WorkerClient,
+    tab_manager: &TabManager,
+    request: WorkerRequest,
+) -> Result<T, Error> {
+    let is_leader = tab_manager
+        .check_leader()
+        .await
+        .map_err(|e| Error::new(ffi::SQLITE_ERROR, format!("{:?}", e)))?;
+    if is_leader {
+        post_to_worker(worker, request).await
+    } else {
+        tab_manager.route_request(request).await
+    }
 }
@@ -0,0 +1,161 @@
+use crate::send;
+use sqlite_wrapper::{
+    ffi, quote_identifier, Error, Params, QueryOptions, ResultSet, RowMode, WorkerClient,
+    WorkerRequest,
+};
+use std::cell::Cell;
+use std::rc::Rc;
+use tab_coordinator::TabManager;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+
+/// A transaction on the leader's connection, returned by
+/// `BrowserSQLite.transaction()`.
+///
+/// Every statement is tagged with the transaction's id, so it runs on the
+/// connection that began it. If leadership moves in the meantime the
+/// transaction is lost and further statements fail.
+///
+/// A transaction freed before it is committed or rolled back is rolled back,
+/// and so is one left idle for too long, since it holds up every other tab.
+#[wasm_bindgen]
+pub struct Transaction {
+    id: String,
+    worker: WorkerClient,
+    tab_manager: Rc<TabManager>,
+    finished: Cell<bool>,
+}
+
+impl Transaction {
+    pub(crate) async fn begin(
+        worker: WorkerClient,
+        tab_manager: Rc<TabManager>,
+    ) -> Result<Transaction, Error> {
+        let id = Uuid::new_v4().to_string();
+        let request = WorkerRequest::Begin {
+            txn: id.clone(),
+            owner: tab_manager.get_tab_id(),
+        };
+        let _: ResultSet = send(&worker, &tab_manager, request).await?;
+
+        Ok(Transaction {
+            id,
+            worker,
+            tab_manager,
+            finished: Cell::new(false),
+        })
+    }
+
+    async fn send(&self, request: WorkerRequest) -> Result<ResultSet, Error> {
+        if self.finished.get() {
+            return Err(Error::new(
+                ffi::SQLITE_MISUSE,
+                "Transaction has already finished",
+            ));
+        }
+        send(&self.worker, &self.tab_manager, request).await
+    }
+
+    async fn execute_sql(&self, sql: String) -> Result<(), Error> {
+        let request = WorkerRequest::Execute {
+            sql,
+            params: Params::None,
+            txn: Some(self.id.clone()),
+        };
+        self.send(request).await.map(|_| ())
+    }
+}
+
+#[wasm_bindgen]
+impl Transaction {
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    /// Executes a write inside the transaction, like `BrowserSQLite.execute`.
+    pub async fn execute(&self, sql: &str, params: JsValue) -> Result<JsValue, JsValue> {
+        let request = WorkerRequest::Execute {
+            sql: sql.to_string(),
+            params: Params::from_js(&params)?,
+            txn: Some(self.id.clone()),
+        };
+        let result = self.send(request).await?;
+        Ok(result.to_js(RowMode::Array))
+    }
+
+    /// Runs a query inside the transaction, like `BrowserSQLite.query`.
+    pub async fn query(
+        &self,
+        sql: &str,
+        params: JsValue,
+        options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let options = QueryOptions::from_js(&options)?;
+        let request = WorkerRequest::Query {
+            sql: sql.to_string(),
+            params: Params::from_js(&params)?,
+            txn: Some(self.id.clone()),
+        };
+        let result = self.send(request).await?;
+        Ok(result.to_js(options.row_mode))
+    }
+
+    /// Opens a nested savepoint.
+    pub async fn savepoint(&self, name: &str) -> Result<(), Error> {
+        self.execute_sql(format!("SAVEPOINT {}", quote_identifier(name)))
+            .await
+    }
+
+    /// Releases a savepoint, keeping its changes.
+    pub async fn release(&self, name: &str) -> Result<(), Error> {
+        self.execute_sql(format!("RELEASE {}", quote_identifier(name)))
+            .await
+    }
+
+    /// Undoes everything since a savepoint. The savepoint stays open.
+    pub async fn rollback_to(&self, name: &str) -> Result<(), Error> {
+        self.execute_sql(format!("ROLLBACK TO {}", quote_identifier(name)))
+            .await
+    }
+
+    pub async fn commit(&self) -> Result<(), Error> {
+        let request = WorkerRequest::Commit {
+            txn: self.id.clone(),
+        };
+        self.send(request).await?;
+        self.finished.set(true);
+        Ok(())
+    }
+
+    pub async fn rollback(&self) -> Result<(), Error> {
+        let request = WorkerRequest::Rollback {
+            txn: self.id.clone(),
+        };
+        // The worker ends the transaction even if ROLLBACK itself fails
+        let result = self.send(request).await;
+        self.finished.set(true);
+        result.map(|_| ())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.finished.get() {
+            return;
+        }
+        let request = WorkerRequest::Rollback {
+            txn: self.id.clone(),
+        };
+        let worker = self.worker.clone();
+        let tab_manager = self.tab_manager.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let result: Result<ResultSet, Error> = send(&worker, &tab_manager, request).await;
+            if let Err(e) = result {
+                web_sys::console::warn_1(
+                    &format!("Could not roll back a dropped transaction: {}", e).into(),
+                );
+            }
+        });
+    }
+}
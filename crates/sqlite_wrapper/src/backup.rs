@@ -0,0 +1,104 @@
+use crate::{ffi, Database, Error};
+use std::ffi::CString;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+impl Database {
+    /// Returns an image of the whole database, as it would be stored in a
+    /// `.sqlite` file.
+    pub fn export(&self) -> Result<Vec<u8>, Error> {
+        let db = self.handle()?;
+        let schema = CString::new("main").unwrap();
+        let mut size = 0;
+
+        let data = unsafe { ffi::sqlite3_serialize(db, schema.as_ptr(), &mut size, 0) };
+        if data.is_null() {
+            // An empty database serializes to nothing rather than failing
+            return match unsafe { ffi::sqlite3_errcode(db) } {
+                ffi::SQLITE_OK => Ok(Vec::new()),
+                _ => Err(unsafe { Error::from_db(db) }),
+            };
+        }
+
+        let bytes = unsafe { std::slice::from_raw_parts(data, size as usize).to_vec() };
+        unsafe { ffi::sqlite3_free(data.cast()) };
+        Ok(bytes)
+    }
+
+    /// Replaces the whole database with `bytes`, an image produced by
+    /// [`Database::export`] or any SQLite database file. On failure the
+    /// database is left as it was.
+    pub fn import(&self, bytes: &[u8]) -> Result<(), Error> {
+        let db = self.handle()?;
+        let source = MemoryDatabase::from_bytes(bytes)?;
+        let schema = CString::new("main").unwrap();
+
+        unsafe {
+            let backup = ffi::sqlite3_backup_init(db, schema.as_ptr(), source.0, schema.as_ptr());
+            if backup.is_null() {
+                return Err(Error::from_db(db));
+            }
+            ffi::sqlite3_backup_step(backup, -1);
+            // finish reports the error of the failed step, if any
+            if ffi::sqlite3_backup_finish(backup) != ffi::SQLITE_OK {
+                return Err(Error::from_db(db));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A private in-memory connection holding a deserialized database image.
+struct MemoryDatabase(*mut ffi::sqlite3);
+
+impl MemoryDatabase {
+    fn from_bytes(bytes: &[u8]) -> Result<MemoryDatabase, Error> {
+        let filename = CString::new(":memory:").unwrap();
+        let mut db = std::ptr::null_mut();
+        let ret = unsafe {
+            ffi::sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+                std::ptr::null(),
+            )
+        };
+        // Dropping closes the handle even when opening failed
+        let memory = MemoryDatabase(db);
+        if ret != ffi::SQLITE_OK {
+            return Err(unsafe { Error::from_db(db) });
+        }
+
+        // SQLite takes ownership of the buffer and frees it on close
+        let len = bytes.len();
+        let data = unsafe { ffi::sqlite3_malloc64(len.max(1) as u64) as *mut u8 };
+        if data.is_null() {
+            return Err(Error::new(ffi::SQLITE_NOMEM, "Out of memory"));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), data, len) };
+
+        let schema = CString::new("main").unwrap();
+        let ret = unsafe {
+            ffi::sqlite3_deserialize(
+                db,
+                schema.as_ptr(),
+                data,
+                len as i64,
+                len as i64,
+                ffi::SQLITE_DESERIALIZE_FREEONCLOSE | ffi::SQLITE_DESERIALIZE_RESIZEABLE,
+            )
+        };
+        if ret != ffi::SQLITE_OK {
+            return Err(unsafe { Error::from_db(db) });
+        }
+
+        Ok(memory)
+    }
+}
+
+impl Drop for MemoryDatabase {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
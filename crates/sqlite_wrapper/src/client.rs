@@ -0,0 +1,148 @@
+use crate::{ffi, Error, WorkerMessage, WorkerReply, WorkerRequest, WorkerResponse};
+use futures::channel::oneshot;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use web_sys::Worker;
+
+type Pending = Rc<RefCell<HashMap<u32, oneshot::Sender<WorkerReply>>>>;
+
+/// The page side of a SQLite worker.
+///
+/// The client owns the worker's `onmessage` handler and hands each response
+/// to the request with the same id, so any number of requests can be in
+/// flight at once. Clones share the same worker and pending requests.
+#[derive(Clone)]
+pub struct WorkerClient {
+    worker: Rc<RefCell<Worker>>,
+    /// Script the worker was started from, if known, so it can be restarted.
+    url: Option<String>,
+    /// The last [`WorkerRequest::Configure`] sent, replayed after a restart.
+    config: Rc<RefCell<Option<WorkerRequest>>>,
+    pending: Pending,
+}
+
+impl WorkerClient {
+    /// Takes over `worker`'s `onmessage` and `onerror` handlers. A client
+    /// made this way cannot [`restart`](WorkerClient::restart) the worker.
+    pub fn new(worker: Worker) -> WorkerClient {
+        WorkerClient::with_url(worker, None)
+    }
+
+    /// Starts a worker from `url`.
+    pub fn spawn(url: &str) -> Result<WorkerClient, JsValue> {
+        Ok(WorkerClient::with_url(
+            Worker::new(url)?,
+            Some(url.to_string()),
+        ))
+    }
+
+    fn with_url(worker: Worker, url: Option<String>) -> WorkerClient {
+        let pending: Pending = Rc::new(RefCell::new(HashMap::new()));
+        attach(&worker, &pending);
+        WorkerClient {
+            worker: Rc::new(RefCell::new(worker)),
+            url,
+            config: Rc::new(RefCell::new(None)),
+            pending,
+        }
+    }
+
+    /// Posts `request` and waits for its reply.
+    pub async fn request(&self, request: WorkerRequest) -> WorkerReply {
+        let message = WorkerMessage::new(request);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.borrow_mut().insert(message.id, sender);
+
+        if let Err(e) = self.worker.borrow().post_message(&message.to_js()) {
+            self.pending.borrow_mut().remove(&message.id);
+            let error = Error::new(ffi::SQLITE_ERROR, format!("{:?}", e));
+            return WorkerReply::Err { error };
+        }
+
+        receiver.await.unwrap_or_else(|_| WorkerReply::Err {
+            error: Error::new(ffi::SQLITE_ERROR, "Worker reply channel closed"),
+        })
+    }
+
+    /// Posts a request the worker does not answer, such as
+    /// [`WorkerRequest::Configure`].
+    pub fn notify(&self, request: WorkerRequest) -> Result<(), JsValue> {
+        if let WorkerRequest::Configure { .. } = request {
+            *self.config.borrow_mut() = Some(request.clone());
+        }
+        self.worker
+            .borrow()
+            .post_message(&WorkerMessage::new(request).to_js())
+    }
+
+    /// Terminates the worker and starts a fresh one with the same
+    /// configuration.
+    ///
+    /// Terminating is the only way to make the worker give up the OPFS sync
+    /// access handles it holds, so another tab's worker can open the
+    /// database. Requests still waiting fail with `SQLITE_ABORT`.
+    pub fn restart(&self) -> Result<(), JsValue> {
+        let url = self
+            .url
+            .as_deref()
+            .ok_or_else(|| JsValue::from_str("Worker was not started from a URL"))?;
+
+        self.worker.borrow().terminate();
+        fail_pending(&self.pending, ffi::SQLITE_ABORT, "SQLite worker restarted");
+
+        let worker = Worker::new(url)?;
+        attach(&worker, &self.pending);
+        *self.worker.borrow_mut() = worker;
+
+        let config = self.config.borrow().clone();
+        if let Some(config) = config {
+            self.notify(config)?;
+        }
+        Ok(())
+    }
+}
+
+/// Installs the handlers that resolve `pending` requests from `worker`.
+fn attach(worker: &Worker, pending: &Pending) {
+    let pending_clone = pending.clone();
+    let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
+        let response = match WorkerResponse::from_js(e.data()) {
+            Ok(response) => response,
+            Err(error) => {
+                web_sys::console::error_1(&error.into());
+                return;
+            }
+        };
+        let sender = response
+            .id
+            .and_then(|id| pending_clone.borrow_mut().remove(&id));
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(response.reply);
+            }
+            None => web_sys::console::warn_1(
+                &format!("Worker response for unknown request: {:?}", response.id).into(),
+            ),
+        }
+    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+    worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+
+    // A worker that failed to load or crashed will never answer
+    let pending_clone = pending.clone();
+    let onerror = Closure::wrap(Box::new(move |e: web_sys::Event| {
+        web_sys::console::error_2(&"SQLite worker failed:".into(), &e);
+        fail_pending(&pending_clone, ffi::SQLITE_ERROR, "SQLite worker failed");
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    worker.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+    onerror.forget();
+}
+
+fn fail_pending(pending: &Pending, code: i32, message: &str) {
+    for (_, sender) in pending.borrow_mut().drain() {
+        let error = Error::new(code, message);
+        let _ = sender.send(WorkerReply::Err { error });
+    }
+}
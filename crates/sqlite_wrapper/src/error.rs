@@ -0,0 +1,156 @@
+use crate::ffi;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+/// An error reported by SQLite, or by this crate using SQLite's result codes.
+///
+/// In JS it becomes an `Error` named `SqliteError` with `code`,
+/// `extendedCode`, `codeName` (e.g. `"SQLITE_CONSTRAINT_UNIQUE"`), `sql`,
+/// `offset` and `statementIndex` properties.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Error {
+    /// Primary result code, e.g. `SQLITE_CONSTRAINT`.
+    pub code: i32,
+    /// Extended result code, e.g. `SQLITE_CONSTRAINT_UNIQUE`.
+    pub extended_code: i32,
+    pub message: String,
+    /// The SQL that failed, when the error came from running SQL.
+    pub sql: Option<String>,
+    /// Byte offset in `sql` that the error refers to, if SQLite knows it.
+    pub offset: Option<usize>,
+    /// Index of the failing statement when running a script.
+    pub statement_index: Option<usize>,
+}
+
+impl Error {
+    pub fn new(code: i32, message: impl Into<String>) -> Error {
+        Error {
+            code: code & 0xff,
+            extended_code: code,
+            message: message.into(),
+            sql: None,
+            offset: None,
+            statement_index: None,
+        }
+    }
+
+    /// Reads the most recent error on `db`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle.
+    pub(crate) unsafe fn from_db(db: *mut ffi::sqlite3) -> Error {
+        let extended_code = ffi::sqlite3_extended_errcode(db);
+        let message = CStr::from_ptr(ffi::sqlite3_errmsg(db))
+            .to_string_lossy()
+            .into_owned();
+        let offset = ffi::sqlite3_error_offset(db);
+
+        Error {
+            offset: usize::try_from(offset).ok(),
+            ..Error::new(extended_code, message)
+        }
+    }
+
+    /// Attaches the SQL that failed.
+    pub fn with_sql(mut self, sql: &str) -> Error {
+        self.sql = Some(sql.to_string());
+        self
+    }
+
+    /// Symbolic name of the extended result code, falling back to the
+    /// primary code's name.
+    pub fn code_name(&self) -> &'static str {
+        code_name(self.extended_code)
+            .or_else(|| code_name(self.code))
+            .unwrap_or("SQLITE_UNKNOWN")
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code_name())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for JsValue {
+    fn from(error: Error) -> JsValue {
+        let js_error = js_sys::Error::new(&error.message);
+        js_error.set_name("SqliteError");
+
+        let set = |key: &str, value: JsValue| {
+            js_sys::Reflect::set(&js_error, &key.into(), &value).unwrap();
+        };
+        set("code", error.code.into());
+        set("extendedCode", error.extended_code.into());
+        set("codeName", error.code_name().into());
+        set("sql", error.sql.map(JsValue::from).unwrap_or(JsValue::NULL));
+        set(
+            "offset",
+            error
+                .offset
+                .map(|o| JsValue::from(o as u32))
+                .unwrap_or(JsValue::NULL),
+        );
+        set(
+            "statementIndex",
+            error
+                .statement_index
+                .map(|i| JsValue::from(i as u32))
+                .unwrap_or(JsValue::NULL),
+        );
+
+        js_error.into()
+    }
+}
+
+macro_rules! code_names {
+    ($code:expr; $($name:ident),* $(,)?) => {
+        match $code {
+            $(ffi::$name => Some(stringify!($name)),)*
+            _ => None,
+        }
+    };
+}
+
+fn code_name(code: i32) -> Option<&'static str> {
+    code_names!(code;
+        SQLITE_OK, SQLITE_ERROR, SQLITE_INTERNAL, SQLITE_PERM, SQLITE_ABORT, SQLITE_BUSY,
+        SQLITE_LOCKED, SQLITE_NOMEM, SQLITE_READONLY, SQLITE_INTERRUPT, SQLITE_IOERR,
+        SQLITE_CORRUPT, SQLITE_NOTFOUND, SQLITE_FULL, SQLITE_CANTOPEN, SQLITE_PROTOCOL,
+        SQLITE_EMPTY, SQLITE_SCHEMA, SQLITE_TOOBIG, SQLITE_CONSTRAINT, SQLITE_MISMATCH,
+        SQLITE_MISUSE, SQLITE_NOLFS, SQLITE_AUTH, SQLITE_FORMAT, SQLITE_RANGE, SQLITE_NOTADB,
+        SQLITE_NOTICE, SQLITE_WARNING, SQLITE_ROW, SQLITE_DONE,
+        SQLITE_ERROR_MISSING_COLLSEQ, SQLITE_ERROR_RETRY, SQLITE_ERROR_SNAPSHOT,
+        SQLITE_IOERR_READ, SQLITE_IOERR_SHORT_READ, SQLITE_IOERR_WRITE, SQLITE_IOERR_FSYNC,
+        SQLITE_IOERR_DIR_FSYNC, SQLITE_IOERR_TRUNCATE, SQLITE_IOERR_FSTAT, SQLITE_IOERR_UNLOCK,
+        SQLITE_IOERR_RDLOCK, SQLITE_IOERR_DELETE, SQLITE_IOERR_BLOCKED, SQLITE_IOERR_NOMEM,
+        SQLITE_IOERR_ACCESS, SQLITE_IOERR_CHECKRESERVEDLOCK, SQLITE_IOERR_LOCK,
+        SQLITE_IOERR_CLOSE, SQLITE_IOERR_DIR_CLOSE, SQLITE_IOERR_SHMOPEN, SQLITE_IOERR_SHMSIZE,
+        SQLITE_IOERR_SHMLOCK, SQLITE_IOERR_SHMMAP, SQLITE_IOERR_SEEK, SQLITE_IOERR_DELETE_NOENT,
+        SQLITE_IOERR_MMAP, SQLITE_IOERR_GETTEMPPATH, SQLITE_IOERR_CONVPATH, SQLITE_IOERR_VNODE,
+        SQLITE_IOERR_AUTH, SQLITE_IOERR_BEGIN_ATOMIC, SQLITE_IOERR_COMMIT_ATOMIC,
+        SQLITE_IOERR_ROLLBACK_ATOMIC, SQLITE_IOERR_DATA, SQLITE_IOERR_CORRUPTFS,
+        SQLITE_IOERR_IN_PAGE,
+        SQLITE_LOCKED_SHAREDCACHE, SQLITE_LOCKED_VTAB,
+        SQLITE_BUSY_RECOVERY, SQLITE_BUSY_SNAPSHOT, SQLITE_BUSY_TIMEOUT,
+        SQLITE_CANTOPEN_NOTEMPDIR, SQLITE_CANTOPEN_ISDIR, SQLITE_CANTOPEN_FULLPATH,
+        SQLITE_CANTOPEN_CONVPATH, SQLITE_CANTOPEN_DIRTYWAL, SQLITE_CANTOPEN_SYMLINK,
+        SQLITE_CORRUPT_VTAB, SQLITE_CORRUPT_SEQUENCE, SQLITE_CORRUPT_INDEX,
+        SQLITE_READONLY_RECOVERY, SQLITE_READONLY_CANTLOCK, SQLITE_READONLY_ROLLBACK,
+        SQLITE_READONLY_DBMOVED, SQLITE_READONLY_CANTINIT, SQLITE_READONLY_DIRECTORY,
+        SQLITE_ABORT_ROLLBACK,
+        SQLITE_CONSTRAINT_CHECK, SQLITE_CONSTRAINT_COMMITHOOK, SQLITE_CONSTRAINT_FOREIGNKEY,
+        SQLITE_CONSTRAINT_FUNCTION, SQLITE_CONSTRAINT_NOTNULL, SQLITE_CONSTRAINT_PRIMARYKEY,
+        SQLITE_CONSTRAINT_TRIGGER, SQLITE_CONSTRAINT_UNIQUE, SQLITE_CONSTRAINT_VTAB,
+        SQLITE_CONSTRAINT_ROWID, SQLITE_CONSTRAINT_PINNED, SQLITE_CONSTRAINT_DATATYPE,
+        SQLITE_NOTICE_RECOVER_WAL, SQLITE_NOTICE_RECOVER_ROLLBACK, SQLITE_NOTICE_RBU,
+        SQLITE_WARNING_AUTOINDEX, SQLITE_AUTH_USER,
+        SQLITE_OK_LOAD_PERMANENTLY, SQLITE_OK_SYMLINK,
+    )
+}
@@ -1,166 +1,453 @@
-use sqlite_wasm_rs::export::{self as ffi, install_opfs_sahpool};
-use std::cell::RefCell;
+#[cfg(not(any(feature = "wasm", feature = "native")))]
+compile_error!("sqlite_wrapper needs the `wasm` or the `native` feature");
+
+#[cfg(not(feature = "native"))]
+use sqlite_wasm_rs::export::install_opfs_sahpool;
 use std::ffi::CString;
-use std::rc::Rc;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::{self, JsFuture};
-use web_sys::DedicatedWorkerGlobalScope;
 
+mod backup;
+mod client;
+mod error;
+mod migrate;
+mod options;
+mod params;
+mod result;
+mod schedule;
+mod value;
+mod worker;
+
+pub use client::WorkerClient;
+pub use error::Error;
+/// Raw SQLite bindings, re-exported for the result code constants.
+#[cfg(feature = "native")]
+pub use libsqlite3_sys as ffi;
+pub use migrate::Migration;
+pub use options::{OpenOptions, Vfs};
+pub use params::Params;
+pub use result::{QueryOptions, ResultSet, RowMode};
+pub use schedule::{Scheduler, Session, SESSION_IDLE_TIMEOUT_MS};
+/// Raw SQLite bindings, re-exported for the result code constants.
+#[cfg(not(feature = "native"))]
+pub use sqlite_wasm_rs::export as ffi;
+pub use value::Value;
+pub use worker::{main, parse_reply, WorkerMessage, WorkerReply, WorkerRequest, WorkerResponse};
+
+/// A single long-lived connection to a SQLite database.
+///
+/// The connection is opened once by [`Database::open`] and reused for every
+/// statement, so connection-scoped state (page cache, temp tables, pragmas)
+/// survives between calls. It is closed by [`Database::close`] or on drop.
 #[wasm_bindgen]
 pub struct Database {
     filename: String,
+    db: *mut ffi::sqlite3,
 }
 
 #[wasm_bindgen]
 impl Database {
-    #[wasm_bindgen(constructor)]
-    pub async fn new(filename: &str) -> Result<Database, JsValue> {
-        // Initialize OPFS once
-        install_opfs_sahpool(None, true)
-            .await
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    /// Opens `filename` in the OPFS SAH pool, creating it if needed.
+    pub async fn new(filename: &str) -> Result<Database, Error> {
+        Database::open(filename, &OpenOptions::default()).await
+    }
 
-        Ok(Database {
-            filename: filename.to_string(),
-        })
+    /// Opens `filename` with `options`, an optional
+    /// `{ vfs, opfsDirectory, opfsCapacity }` object. `vfs` is `"opfs"` (the
+    /// default) or `"memory"`.
+    #[wasm_bindgen(js_name = open)]
+    pub async fn open_js(filename: &str, options: JsValue) -> Result<Database, Error> {
+        Database::open(filename, &OpenOptions::from_js(&options)?).await
     }
 
-    pub fn execute(&self, sql: &str) -> Result<(), JsValue> {
-        // Open DB
-        let mut db = std::ptr::null_mut();
-        let filename = CString::new(self.filename.as_str()).unwrap();
-        let ret = unsafe {
-            ffi::sqlite3_open_v2(
-                filename.as_ptr(),
-                &mut db,
-                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
-                std::ptr::null(),
-            )
-        };
+    #[wasm_bindgen(getter)]
+    pub fn filename(&self) -> String {
+        self.filename.clone()
+    }
 
-        if ret != ffi::SQLITE_OK {
-            return Err(JsValue::from_str("Failed to open database"));
+    /// Closes the connection. Any further call on this database fails.
+    pub fn close(&mut self) -> Result<(), Error> {
+        if self.db.is_null() {
+            return Ok(());
         }
 
-        // Execute SQL
-        let sql = CString::new(sql).unwrap();
-        let mut err_msg = std::ptr::null_mut();
-        let ret = unsafe {
-            ffi::sqlite3_exec(db, sql.as_ptr(), None, std::ptr::null_mut(), &mut err_msg)
-        };
-
-        // Close DB
-        unsafe { ffi::sqlite3_close(db) };
-
+        let ret = unsafe { ffi::sqlite3_close(self.db) };
         if ret != ffi::SQLITE_OK {
-            let error = unsafe { CString::from_raw(err_msg).into_string().unwrap() };
-            unsafe { ffi::sqlite3_free(err_msg as *mut _) };
-            return Err(JsValue::from_str(&error));
+            return Err(unsafe { Error::from_db(self.db) });
         }
+        self.db = std::ptr::null_mut();
 
         Ok(())
     }
 
-    pub fn query(&self, sql: &str) -> Result<JsValue, JsValue> {
+    /// Executes SQL for its side effects. `params` is an optional array of
+    /// positional or object of named parameters. Resolves to a result with
+    /// `rowsAffected` and `lastInsertRowid`.
+    #[wasm_bindgen(js_name = execute)]
+    pub fn execute_js(&self, sql: &str, params: JsValue) -> Result<JsValue, Error> {
+        self.execute(sql, &Params::from_js(&params)?)
+            .map(|result| result.to_js(RowMode::Array))
+    }
+
+    /// Runs a query and returns its columns, declared types and rows.
+    /// Pass `{ rowMode: "object" }` as `options` to get rows keyed by column
+    /// name.
+    #[wasm_bindgen(js_name = query)]
+    pub fn query_js(&self, sql: &str, params: JsValue, options: JsValue) -> Result<JsValue, Error> {
+        let options = QueryOptions::from_js(&options)?;
+        self.query(sql, &Params::from_js(&params)?)
+            .map(|result| result.to_js(options.row_mode))
+    }
+
+    /// Runs every statement in `sql` and returns an array with one result per
+    /// statement.
+    #[wasm_bindgen(js_name = execute_script)]
+    pub fn execute_script_js(&self, sql: &str, options: JsValue) -> Result<JsValue, Error> {
+        let options = QueryOptions::from_js(&options)?;
+        let results = self.execute_script(sql)?;
+        Ok(results
+            .iter()
+            .map(|result| result.to_js(options.row_mode))
+            .collect::<js_sys::Array>()
+            .into())
+    }
+
+    /// Starts a transaction. Use savepoints to nest inside one.
+    pub fn begin(&self) -> Result<(), Error> {
+        self.run("BEGIN")
+    }
+
+    /// Commits the open transaction.
+    pub fn commit(&self) -> Result<(), Error> {
+        self.run("COMMIT")
+    }
+
+    /// Rolls back the open transaction.
+    pub fn rollback(&self) -> Result<(), Error> {
+        self.run("ROLLBACK")
+    }
+
+    /// Opens a savepoint, starting a transaction if none is open.
+    pub fn savepoint(&self, name: &str) -> Result<(), Error> {
+        self.run(&format!("SAVEPOINT {}", quote_identifier(name)))
+    }
+
+    /// Releases a savepoint, keeping its changes in the enclosing
+    /// transaction.
+    pub fn release(&self, name: &str) -> Result<(), Error> {
+        self.run(&format!("RELEASE {}", quote_identifier(name)))
+    }
+
+    /// Undoes everything since a savepoint. The savepoint stays open.
+    pub fn rollback_to(&self, name: &str) -> Result<(), Error> {
+        self.run(&format!("ROLLBACK TO {}", quote_identifier(name)))
+    }
+
+    /// Whether a transaction is open on this connection.
+    #[wasm_bindgen(getter)]
+    pub fn in_transaction(&self) -> bool {
+        !self.db.is_null() && unsafe { ffi::sqlite3_get_autocommit(self.db) } == 0
+    }
+}
+
+impl Database {
+    /// Opens `filename` with the storage described by `options`, creating
+    /// it if needed.
+    pub async fn open(filename: &str, options: &OpenOptions) -> Result<Database, Error> {
+        let (path, vfs) = Database::locate(filename, options).await?;
+
         // Open DB
         let mut db = std::ptr::null_mut();
-        let filename = CString::new(self.filename.as_str()).unwrap();
+        let c_path = CString::new(path)
+            .map_err(|_| Error::new(ffi::SQLITE_CANTOPEN, "Filename contains a NUL character"))?;
+        let c_vfs = vfs.map(|vfs| CString::new(vfs).unwrap());
         let ret = unsafe {
             ffi::sqlite3_open_v2(
-                filename.as_ptr(),
+                c_path.as_ptr(),
                 &mut db,
                 ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
-                std::ptr::null(),
+                c_vfs.as_ref().map_or(std::ptr::null(), |vfs| vfs.as_ptr()),
             )
         };
 
         if ret != ffi::SQLITE_OK {
-            return Err(JsValue::from_str("Failed to open database"));
+            // A handle is allocated even when opening fails
+            let error = unsafe { Error::from_db(db) };
+            unsafe { ffi::sqlite3_close(db) };
+            return Err(error);
         }
 
-        // Query logic
-        let sql = CString::new(sql).unwrap();
-        let mut stmt = std::ptr::null_mut();
-        let mut results = Vec::new();
+        Ok(Database {
+            filename: filename.to_string(),
+            db,
+        })
+    }
 
-        let ret = unsafe {
-            ffi::sqlite3_prepare_v2(db, sql.as_ptr(), -1, &mut stmt, std::ptr::null_mut())
+    /// The path to open `filename` at and the VFS to open it with, `None`
+    /// meaning SQLite's default.
+    #[cfg(not(feature = "native"))]
+    async fn locate(
+        filename: &str,
+        options: &OpenOptions,
+    ) -> Result<(String, Option<String>), Error> {
+        let vfs = match options.sahpool() {
+            Some(cfg) => {
+                install_opfs_sahpool(Some(&cfg), false)
+                    .await
+                    .map_err(|e| Error::new(ffi::SQLITE_CANTOPEN, e.to_string()))?;
+                cfg.vfs_name
+            }
+            // Registered by sqlite-wasm-rs when it initializes
+            None => "memvfs".to_string(),
         };
+        Ok((filename.to_string(), Some(vfs)))
+    }
 
-        if ret != ffi::SQLITE_OK {
-            unsafe { ffi::sqlite3_close(db) };
-            return Err(JsValue::from_str("Failed to prepare statement"));
-        }
-
-        while unsafe { ffi::sqlite3_step(stmt) } == ffi::SQLITE_ROW {
-            let mut row = Vec::new();
-            let cols = unsafe { ffi::sqlite3_column_count(stmt) };
-
-            for i in 0..cols {
-                let value = unsafe {
-                    let text = ffi::sqlite3_column_text(stmt, i);
-                    if text.is_null() {
-                        JsValue::NULL
-                    } else {
-                        let str_val = std::ffi::CStr::from_ptr(text as *mut i8)
-                            .to_str()
-                            .unwrap_or("invalid utf8");
-                        JsValue::from_str(str_val)
+    /// The path to open `filename` at and the VFS to open it with, `None`
+    /// meaning SQLite's default.
+    #[cfg(feature = "native")]
+    async fn locate(
+        filename: &str,
+        options: &OpenOptions,
+    ) -> Result<(String, Option<String>), Error> {
+        match options.vfs {
+            Vfs::Opfs => {
+                let path = match &options.opfs_directory {
+                    Some(directory) => {
+                        std::fs::create_dir_all(directory).map_err(|e| {
+                            Error::new(ffi::SQLITE_CANTOPEN, format!("{}: {}", directory, e))
+                        })?;
+                        std::path::Path::new(directory).join(filename)
                     }
+                    None => filename.into(),
                 };
-                row.push(value);
+                Ok((path.to_string_lossy().into_owned(), None))
             }
-            results.push(js_sys::Array::from_iter(row));
+            // A leading `/` lets other connections in this process share it
+            Vfs::Memory => Ok((format!("/{}", filename), Some("memdb".to_string()))),
         }
+    }
 
-        unsafe {
-            ffi::sqlite3_finalize(stmt);
-            ffi::sqlite3_close(db);
+    /// Runs `f` in a transaction, committing if it returns `Ok` and rolling
+    /// back if it returns `Err` or the commit fails. Inside an open
+    /// transaction it nests as a savepoint instead.
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Database) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        const SAVEPOINT: &str = "transaction";
+
+        let nested = self.in_transaction();
+        if nested {
+            self.savepoint(SAVEPOINT)?;
+        } else {
+            self.begin()?;
+        }
+
+        let result = f(self).and_then(|value| {
+            if nested {
+                self.release(SAVEPOINT)?;
+            } else {
+                self.commit()?;
+            }
+            Ok(value)
+        });
+        if result.is_err() {
+            // SQLite keeps the transaction open when the commit fails, e.g.
+            // on a deferred foreign key violation or SQLITE_BUSY. The
+            // original error matters more than a failed rollback.
+            let _ = if nested {
+                self.rollback_to(SAVEPOINT)
+                    .and_then(|_| self.release(SAVEPOINT))
+            } else {
+                self.rollback()
+            };
+        }
+        result
+    }
+
+    /// Executes SQL for its side effects. Without parameters the SQL may
+    /// hold several statements; with parameters it must be a single one.
+    pub fn execute(&self, sql: &str, params: &Params) -> Result<ResultSet, Error> {
+        if !params.is_empty() {
+            return self.query(sql, params).map(|result| ResultSet {
+                rows: Vec::new(),
+                ..result
+            });
         }
 
-        Ok(js_sys::Array::from_iter(results).into())
+        let results = self.execute_script(sql)?;
+        Ok(ResultSet {
+            rows_affected: results.iter().map(|r| r.rows_affected).sum(),
+            last_insert_rowid: results
+                .iter()
+                .rev()
+                .map(|r| r.last_insert_rowid)
+                .find(|&rowid| rowid != 0)
+                .unwrap_or(0),
+            ..ResultSet::default()
+        })
     }
-}
 
-#[wasm_bindgen]
-pub async fn main() -> Result<(), JsValue> {
-    web_sys::console::log_1(&JsValue::from_str("Setting up worker..."));
-    let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
-    let scope_clone = scope.clone();
-
-    // Specify the type for Option<Database>
-    let db: Rc<RefCell<Option<Database>>> = Rc::new(RefCell::new(None));
-    let db_clone = db.clone();
-
-    let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
-        if let Some(msg) = e.data().as_string() {
-            let msg = msg.to_string();
-            let scope_clone = scope_clone.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                web_sys::console::log_1(&format!("Worker received: {}", msg).into());
-
-                let result = if msg.starts_with("QUERY:") {
-                    match Database::new("app.db").await {
-                        Ok(db) => db.query(&msg[6..]),
-                        Err(e) => Err(e),
-                    }
-                } else {
-                    match Database::new("app.db").await {
-                        Ok(db) => db.execute(&msg).map(|_| JsValue::NULL),
-                        Err(e) => Err(e),
-                    }
-                };
-                match result {
-                    Ok(val) => scope_clone.post_message(&val),
-                    Err(e) => scope_clone.post_message(&e),
+    /// Runs a single statement and returns its result set.
+    pub fn query(&self, sql: &str, params: &Params) -> Result<ResultSet, Error> {
+        let db = self.handle()?;
+        let c_sql = CString::new(sql).map_err(|_| nul_error(sql))?;
+        let mut stmt = std::ptr::null_mut();
+        let mut tail = std::ptr::null();
+
+        let ret = unsafe { ffi::sqlite3_prepare_v2(db, c_sql.as_ptr(), -1, &mut stmt, &mut tail) };
+
+        if ret != ffi::SQLITE_OK {
+            return Err(unsafe { Error::from_db(db) }.with_sql(sql));
+        }
+
+        // Anything after the first statement would otherwise be dropped
+        if unsafe { has_statement(db, tail) } {
+            unsafe { ffi::sqlite3_finalize(stmt) };
+            let error = Error::new(
+                ffi::SQLITE_MISUSE,
+                "query runs a single statement; use execute_script for several",
+            );
+            return Err(error.with_sql(sql));
+        }
+
+        let result = unsafe { params.bind(stmt).and_then(|_| run_statement(db, stmt)) };
+        unsafe { ffi::sqlite3_finalize(stmt) };
+        result.map_err(|e| e.with_sql(sql))
+    }
+
+    /// Runs every statement in `sql` in order and returns one result set per
+    /// statement. Stops at the first failing statement and reports its
+    /// index and byte offset in `sql`.
+    pub fn execute_script(&self, sql: &str) -> Result<Vec<ResultSet>, Error> {
+        let db = self.handle()?;
+        let c_sql = CString::new(sql).map_err(|_| nul_error(sql))?;
+        let start = c_sql.as_ptr();
+        let mut next = start;
+        let mut results = Vec::new();
+
+        loop {
+            let offset = next as usize - start as usize;
+            if offset >= sql.len() {
+                break;
+            }
+
+            let mut stmt = std::ptr::null_mut();
+            let mut tail = std::ptr::null();
+            let ret = unsafe { ffi::sqlite3_prepare_v2(db, next, -1, &mut stmt, &mut tail) };
+            let result = if ret != ffi::SQLITE_OK {
+                Err(unsafe { Error::from_db(db) })
+            } else if stmt.is_null() {
+                // An empty statement, such as a stray `;` or a comment
+                if tail == next {
+                    break;
                 }
-                .unwrap();
-            });
+                next = tail;
+                continue;
+            } else {
+                let result = unsafe { run_statement(db, stmt) };
+                unsafe { ffi::sqlite3_finalize(stmt) };
+                result
+            };
+
+            match result {
+                Ok(result) => results.push(result),
+                Err(e) => {
+                    // Offsets from SQLite are relative to the failing statement
+                    return Err(Error {
+                        sql: Some(sql.to_string()),
+                        offset: Some(offset + e.offset.unwrap_or(0)),
+                        statement_index: Some(results.len()),
+                        ..e
+                    });
+                }
+            }
+            next = tail;
         }
-    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
 
-    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
-    onmessage.forget();
+        Ok(results)
+    }
+
+    pub(crate) fn run(&self, sql: &str) -> Result<(), Error> {
+        self.execute_script(sql).map(|_| ())
+    }
+
+    pub(crate) fn handle(&self) -> Result<*mut ffi::sqlite3, Error> {
+        if self.db.is_null() {
+            return Err(Error::new(ffi::SQLITE_MISUSE, "Database is closed"));
+        }
+        Ok(self.db)
+    }
+}
+
+/// Steps a prepared statement to completion, collecting its rows.
+unsafe fn run_statement(
+    db: *mut ffi::sqlite3,
+    stmt: *mut ffi::sqlite3_stmt,
+) -> Result<ResultSet, Error> {
+    let mut results = ResultSet::with_columns(stmt);
+    let rowid_before = ffi::sqlite3_last_insert_rowid(db);
+
+    let ret = loop {
+        let ret = ffi::sqlite3_step(stmt);
+        if ret != ffi::SQLITE_ROW {
+            break ret;
+        }
+        let row = (0..results.columns.len() as i32)
+            .map(|i| Value::from_column(stmt, i))
+            .collect();
+        results.rows.push(row);
+    };
+
+    if ret != ffi::SQLITE_DONE {
+        return Err(Error::from_db(db));
+    }
+
+    // Reads leave the connection's change counter untouched
+    if ffi::sqlite3_stmt_readonly(stmt) == 0 {
+        results.rows_affected = ffi::sqlite3_changes64(db) as u64;
+        // The connection keeps the rowid of its last insert, whichever
+        // statement made it
+        let rowid = ffi::sqlite3_last_insert_rowid(db);
+        if results.rows_affected > 0 && rowid != rowid_before {
+            results.last_insert_rowid = rowid;
+        }
+    }
 
-    Ok(())
+    Ok(results)
+}
+
+/// Whether `sql` holds another statement rather than just whitespace,
+/// comments and empty statements.
+unsafe fn has_statement(db: *mut ffi::sqlite3, mut sql: *const std::os::raw::c_char) -> bool {
+    loop {
+        let mut stmt = std::ptr::null_mut();
+        let mut tail = std::ptr::null();
+        let ret = ffi::sqlite3_prepare_v2(db, sql, -1, &mut stmt, &mut tail);
+        if ret != ffi::SQLITE_OK || !stmt.is_null() {
+            ffi::sqlite3_finalize(stmt);
+            return true;
+        }
+        if tail == sql || *tail == 0 {
+            return false;
+        }
+        sql = tail;
+    }
+}
+
+/// Quotes `name` as an SQL identifier, e.g. for a savepoint name.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn nul_error(sql: &str) -> Error {
+    Error::new(ffi::SQLITE_MISUSE, "SQL contains a NUL character").with_sql(sql)
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if !self.db.is_null() {
+            unsafe { ffi::sqlite3_close(self.db) };
+        }
+    }
 }
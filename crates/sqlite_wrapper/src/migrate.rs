@@ -0,0 +1,105 @@
+use crate::{ffi, Database, Error, Params, Value};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+/// One step in a schema's history. `version` is the `PRAGMA user_version`
+/// the database is at once `sql` has run.
+///
+/// In JS a migration is `{ version: 1, sql: "CREATE TABLE ..." }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub version: i32,
+    pub sql: String,
+}
+
+impl Migration {
+    pub fn new(version: i32, sql: impl Into<String>) -> Migration {
+        Migration {
+            version,
+            sql: sql.into(),
+        }
+    }
+
+    pub fn list_from_js(value: &JsValue) -> Result<Vec<Migration>, Error> {
+        serde_wasm_bindgen::from_value(value.clone())
+            .map_err(|e| Error::new(ffi::SQLITE_MISUSE, format!("Invalid migrations: {}", e)))
+    }
+
+    /// The version the last of `migrations` reaches, 0 if there are none.
+    /// Fails unless they are in strictly increasing version order.
+    pub fn latest_version(migrations: &[Migration]) -> Result<i32, Error> {
+        let mut previous = 0;
+        for migration in migrations {
+            if migration.version <= previous {
+                return Err(Error::new(
+                    ffi::SQLITE_MISUSE,
+                    format!(
+                        "Migration versions must be positive and increasing, got {} after {}",
+                        migration.version, previous
+                    ),
+                ));
+            }
+            previous = migration.version;
+        }
+        Ok(previous)
+    }
+
+    /// Fails if a database at schema version `current` is newer than
+    /// `latest`, since code that only knows up to `latest` cannot know that
+    /// schema.
+    pub fn check_known(current: i32, latest: i32) -> Result<(), Error> {
+        if current > latest {
+            return Err(Error::new(
+                ffi::SQLITE_CANTOPEN,
+                format!(
+                    "Database schema version {} is newer than the latest known version {}",
+                    current, latest
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl Database {
+    /// The schema version stored in `PRAGMA user_version`.
+    pub fn user_version(&self) -> Result<i32, Error> {
+        let result = self.query("PRAGMA user_version", &Params::None)?;
+        match result.rows.first().and_then(|row| row.first()) {
+            Some(Value::Integer(version)) => Ok(*version as i32),
+            _ => Ok(0),
+        }
+    }
+
+    /// Applies every migration newer than the current schema version, in one
+    /// transaction, and returns the version reached. `migrations` is an
+    /// array of `{ version, sql }` objects.
+    #[wasm_bindgen(js_name = migrate)]
+    pub fn migrate_js(&self, migrations: JsValue) -> Result<i32, Error> {
+        self.migrate(&Migration::list_from_js(&migrations)?)
+    }
+}
+
+impl Database {
+    /// Applies every migration newer than the current schema version, in one
+    /// transaction, and returns the version reached.
+    ///
+    /// `migrations` must be in strictly increasing version order. Fails
+    /// without touching the database if it is already at a version newer
+    /// than the last migration, since this code cannot know that schema.
+    pub fn migrate(&self, migrations: &[Migration]) -> Result<i32, Error> {
+        let latest = Migration::latest_version(migrations)?;
+
+        self.transaction(|db| {
+            let current = db.user_version()?;
+            Migration::check_known(current, latest)?;
+
+            for migration in migrations.iter().filter(|m| m.version > current) {
+                db.execute_script(&migration.sql)?;
+                db.run(&format!("PRAGMA user_version = {}", migration.version))?;
+            }
+            Ok(latest)
+        })
+    }
+}
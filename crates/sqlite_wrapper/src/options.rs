@@ -0,0 +1,62 @@
+use crate::{ffi, Error};
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "native"))]
+use sqlite_wasm_rs::export::OpfsSAHPoolCfg;
+use wasm_bindgen::prelude::*;
+
+/// Where a database's pages are stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Vfs {
+    /// A file in the OPFS SAH pool, persisted across reloads. With the
+    /// `native` feature, a plain file under `opfs_directory`.
+    #[default]
+    Opfs,
+    /// Memory only, lost when the worker (or natively, the process) goes
+    /// away.
+    Memory,
+}
+
+/// How [`Database::open`](crate::Database::open) opens a database.
+///
+/// In JS these are `{ vfs, opfsDirectory, opfsCapacity }`, all optional.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpenOptions {
+    pub vfs: Vfs,
+    /// OPFS directory holding the SAH pool. Databases in different
+    /// directories get separate pools.
+    pub opfs_directory: Option<String>,
+    /// Number of files the SAH pool holds, including journals.
+    pub opfs_capacity: Option<u32>,
+}
+
+impl OpenOptions {
+    pub fn from_js(value: &JsValue) -> Result<OpenOptions, Error> {
+        if value.is_null() || value.is_undefined() {
+            return Ok(OpenOptions::default());
+        }
+        serde_wasm_bindgen::from_value(value.clone())
+            .map_err(|e| Error::new(ffi::SQLITE_MISUSE, format!("Invalid open options: {}", e)))
+    }
+
+    /// The SAH pool configuration, or `None` for in-memory databases.
+    #[cfg(not(feature = "native"))]
+    pub(crate) fn sahpool(&self) -> Option<OpfsSAHPoolCfg> {
+        if self.vfs != Vfs::Opfs {
+            return None;
+        }
+
+        let mut cfg = OpfsSAHPoolCfg::default();
+        if let Some(directory) = &self.opfs_directory {
+            // The pool is registered once per VFS name, so each directory
+            // needs a name of its own
+            cfg.vfs_name = format!("{}:{}", cfg.vfs_name, directory);
+            cfg.directory = directory.clone();
+        }
+        if let Some(capacity) = self.opfs_capacity {
+            cfg.initial_capacity = capacity;
+        }
+        Some(cfg)
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{ffi, Error, Value};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::ffi::CString;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+/// Parameters bound to a statement with `sqlite3_bind_*`.
+///
+/// In JS, positional parameters are an array (`[1, "a"]` binds `?1` and
+/// `?2`) and named parameters are an object (`{ name: "a" }` binds `:name`,
+/// `@name` or `$name`). `null` or `undefined` binds nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Params {
+    #[default]
+    None,
+    Positional(Vec<Value>),
+    Named(Vec<(String, Value)>),
+}
+
+impl Params {
+    pub fn from_js(value: &JsValue) -> Result<Params, Error> {
+        serde_wasm_bindgen::from_value(value.clone())
+            .map_err(|e| Error::new(ffi::SQLITE_MISMATCH, format!("Invalid parameters: {}", e)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Params::None => true,
+            Params::Positional(values) => values.is_empty(),
+            Params::Named(values) => values.is_empty(),
+        }
+    }
+
+    /// Binds these parameters to `stmt`.
+    ///
+    /// # Safety
+    ///
+    /// `stmt` must be a valid, freshly prepared or reset statement.
+    pub(crate) unsafe fn bind(&self, stmt: *mut ffi::sqlite3_stmt) -> Result<(), Error> {
+        let count = ffi::sqlite3_bind_parameter_count(stmt);
+        match self {
+            Params::None => check_count(count, 0),
+            Params::Positional(values) => {
+                // Unbound parameters would silently be NULL
+                check_count(count, values.len())?;
+                for (i, value) in values.iter().enumerate() {
+                    bind_value(stmt, i as i32 + 1, value)?;
+                }
+                Ok(())
+            }
+            Params::Named(values) => {
+                for (name, value) in values {
+                    let index = parameter_index(stmt, name);
+                    if index == 0 {
+                        return Err(Error::new(
+                            ffi::SQLITE_RANGE,
+                            format!("Unknown parameter: {}", name),
+                        ));
+                    }
+                    bind_value(stmt, index, value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Fails unless a statement taking `count` parameters was given exactly
+/// that many.
+fn check_count(count: i32, given: usize) -> Result<(), Error> {
+    if given != count as usize {
+        return Err(Error::new(
+            ffi::SQLITE_RANGE,
+            format!(
+                "Statement takes {} parameters but {} were given",
+                count, given
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Looks up a named parameter, accepting the name with or without its
+/// `:`, `@` or `$` prefix. Returns 0 when there is no such parameter.
+unsafe fn parameter_index(stmt: *mut ffi::sqlite3_stmt, name: &str) -> i32 {
+    let candidates = if name.starts_with([':', '@', '$']) {
+        vec![name.to_string()]
+    } else {
+        vec![
+            format!(":{}", name),
+            format!("@{}", name),
+            format!("${}", name),
+        ]
+    };
+
+    candidates
+        .into_iter()
+        .filter_map(|name| CString::new(name).ok())
+        .map(|name| ffi::sqlite3_bind_parameter_index(stmt, name.as_ptr()))
+        .find(|&index| index != 0)
+        .unwrap_or(0)
+}
+
+unsafe fn bind_value(stmt: *mut ffi::sqlite3_stmt, index: i32, value: &Value) -> Result<(), Error> {
+    let ret = match value {
+        Value::Null => ffi::sqlite3_bind_null(stmt, index),
+        Value::Integer(v) => ffi::sqlite3_bind_int64(stmt, index, *v),
+        Value::Real(v) => ffi::sqlite3_bind_double(stmt, index, *v),
+        Value::Text(v) => ffi::sqlite3_bind_text(
+            stmt,
+            index,
+            v.as_ptr().cast(),
+            v.len() as i32,
+            ffi::SQLITE_TRANSIENT(),
+        ),
+        Value::Blob(v) => ffi::sqlite3_bind_blob(
+            stmt,
+            index,
+            v.as_ptr().cast(),
+            v.len() as i32,
+            ffi::SQLITE_TRANSIENT(),
+        ),
+    };
+
+    if ret != ffi::SQLITE_OK {
+        return Err(Error::from_db(ffi::sqlite3_db_handle(stmt)));
+    }
+    Ok(())
+}
+
+impl Serialize for Params {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Params::None => serializer.serialize_unit(),
+            Params::Positional(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Params::Named(values) => {
+                let mut map = serializer.serialize_map(Some(values.len()))?;
+                for (name, value) in values {
+                    map.serialize_entry(name, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Params {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Params, D::Error> {
+        deserializer.deserialize_any(ParamsVisitor)
+    }
+}
+
+struct ParamsVisitor;
+
+impl<'de> Visitor<'de> for ParamsVisitor {
+    type Value = Params;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("null, an array of positional parameters or an object of named parameters")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Params, E> {
+        Ok(Params::None)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Params, E> {
+        Ok(Params::None)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Params, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element::<Value>()? {
+            values.push(value);
+        }
+        Ok(Params::Positional(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Params, A::Error> {
+        let mut values = Vec::new();
+        while let Some(entry) = map.next_entry::<String, Value>()? {
+            values.push(entry);
+        }
+        Ok(Params::Named(values))
+    }
+}
@@ -0,0 +1,132 @@
+use crate::value::{serialize_integer, tagged_rows, Value};
+use crate::{ffi, Error};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use wasm_bindgen::prelude::*;
+
+/// The outcome of running one statement: its columns, its rows and, for
+/// writes, how many rows it changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    /// Declared type of each column, `None` for expressions.
+    pub decl_types: Vec<Option<String>>,
+    /// Sent between the worker and tabs with every cell tagged, see
+    /// [`to_js`](ResultSet::to_js) for the plain JS form.
+    #[serde(with = "tagged_rows")]
+    pub rows: Vec<Vec<Value>>,
+    pub rows_affected: u64,
+    /// Rowid of the last row the statement inserted, 0 if it inserted none.
+    /// An insert that reuses the rowid of the connection's previous insert
+    /// also reads as 0.
+    #[serde(serialize_with = "serialize_integer")]
+    pub last_insert_rowid: i64,
+}
+
+/// How rows are shaped when a [`ResultSet`] is handed to JS.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RowMode {
+    /// Each row is an array of values in column order.
+    #[default]
+    Array,
+    /// Each row is an object keyed by column name.
+    Object,
+}
+
+/// Options for `query` calls made from JS, e.g. `{ rowMode: "object" }`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueryOptions {
+    pub row_mode: RowMode,
+}
+
+impl QueryOptions {
+    pub fn from_js(value: &JsValue) -> Result<QueryOptions, Error> {
+        if value.is_null() || value.is_undefined() {
+            return Ok(QueryOptions::default());
+        }
+        serde_wasm_bindgen::from_value(value.clone())
+            .map_err(|e| Error::new(ffi::SQLITE_MISUSE, format!("Invalid query options: {}", e)))
+    }
+}
+
+impl ResultSet {
+    /// Reads the column names and declared types of a prepared statement.
+    ///
+    /// # Safety
+    ///
+    /// `stmt` must be a valid prepared statement.
+    pub(crate) unsafe fn with_columns(stmt: *mut ffi::sqlite3_stmt) -> ResultSet {
+        let cols = ffi::sqlite3_column_count(stmt);
+        let (columns, decl_types) = (0..cols)
+            .map(|i| {
+                let name = ffi::sqlite3_column_name(stmt, i);
+                let decl_type = ffi::sqlite3_column_decltype(stmt, i);
+                (c_string(name).unwrap_or_default(), c_string(decl_type))
+            })
+            .unzip();
+
+        ResultSet {
+            columns,
+            decl_types,
+            ..ResultSet::default()
+        }
+    }
+
+    /// Converts to a JS object with `columns`, `declTypes`, `rows`,
+    /// `rowsAffected` and `lastInsertRowid`.
+    pub fn to_js(&self, row_mode: RowMode) -> JsValue {
+        let rows: js_sys::Array = match row_mode {
+            RowMode::Array => self
+                .rows
+                .iter()
+                .map(|row| row.iter().map(Value::to_js).collect::<js_sys::Array>())
+                .collect(),
+            RowMode::Object => self
+                .rows
+                .iter()
+                .map(|row| {
+                    let object = js_sys::Object::new();
+                    for (column, value) in self.columns.iter().zip(row) {
+                        js_sys::Reflect::set(&object, &column.into(), &value.to_js()).unwrap();
+                    }
+                    object
+                })
+                .collect(),
+        };
+
+        let columns: js_sys::Array = self.columns.iter().map(JsValue::from).collect();
+        let decl_types: js_sys::Array = self
+            .decl_types
+            .iter()
+            .map(|t| t.as_deref().map(JsValue::from).unwrap_or(JsValue::NULL))
+            .collect();
+
+        let result = js_sys::Object::new();
+        let set = |key: &str, value: &JsValue| {
+            js_sys::Reflect::set(&result, &key.into(), value).unwrap();
+        };
+        set("columns", &columns);
+        set("declTypes", &decl_types);
+        set("rows", &rows);
+        set(
+            "rowsAffected",
+            &JsValue::from_f64(self.rows_affected as f64),
+        );
+        set(
+            "lastInsertRowid",
+            &Value::Integer(self.last_insert_rowid).to_js(),
+        );
+        result.into()
+    }
+}
+
+unsafe fn c_string(ptr: *const std::os::raw::c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{WorkerMessage, WorkerRequest};
+use std::collections::VecDeque;
+
+/// How long a transaction may go without a statement before the worker rolls
+/// it back. Otherwise a tab that began one and never finished it, e.g.
+/// because it threw, would hold up every other tab for as long as it lives.
+pub const SESSION_IDLE_TIMEOUT_MS: f64 = 30_000.0;
+
+/// The transaction currently open on the worker's connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub txn: String,
+    /// The tab that began it.
+    pub owner: String,
+    /// When the last of its requests started.
+    last_active: f64,
+}
+
+impl Session {
+    /// Whether `request` may run while this transaction is open.
+    fn admits(&self, request: &WorkerRequest) -> bool {
+        matches!(request, WorkerRequest::Abandon { .. }) || request.txn() == Some(&self.txn)
+    }
+}
+
+/// The order the SQLite worker runs messages in. Messages run one at a time
+/// as they arrive, but while a transaction is open the ones outside it are
+/// set aside and put back at the front of the queue once it ends.
+#[derive(Default)]
+pub struct Scheduler {
+    queue: VecDeque<WorkerMessage>,
+    session: Option<Session>,
+    deferred: VecDeque<WorkerMessage>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    pub fn push(&mut self, message: WorkerMessage) {
+        self.queue.push_back(message);
+    }
+
+    /// The next message that may run at `now`, if any.
+    pub fn next(&mut self, now: f64) -> Option<WorkerMessage> {
+        while let Some(message) = self.queue.pop_front() {
+            let Some(session) = &mut self.session else {
+                return Some(message);
+            };
+            if session.admits(&message.request) {
+                session.last_active = now;
+                return Some(message);
+            }
+            self.deferred.push_back(message);
+        }
+        None
+    }
+
+    /// The open transaction, if any.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Records that transaction `txn` of tab `owner` has begun.
+    pub fn begin(&mut self, txn: String, owner: String, now: f64) {
+        self.session = Some(Session {
+            txn,
+            owner,
+            last_active: now,
+        });
+    }
+
+    /// Records that the open transaction has ended, letting the messages
+    /// that waited for it run.
+    pub fn end(&mut self) {
+        self.session = None;
+        for message in self.deferred.drain(..).rev() {
+            self.queue.push_front(message);
+        }
+    }
+
+    /// When the open transaction times out unless another of its requests
+    /// runs first.
+    pub fn deadline(&self) -> Option<f64> {
+        self.session
+            .as_ref()
+            .map(|session| session.last_active + SESSION_IDLE_TIMEOUT_MS)
+    }
+
+    /// Ends the open transaction if it has been idle too long by `now`,
+    /// returning it for the caller to roll back.
+    pub fn expire(&mut self, now: f64) -> Option<Session> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return None;
+        }
+        let session = self.session.clone();
+        self.end();
+        session
+    }
+}
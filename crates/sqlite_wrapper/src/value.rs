@@ -0,0 +1,273 @@
+use crate::{ffi, Error};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// Largest integer a JS number can hold without losing precision.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// A single SQLite value, typed by its storage class.
+///
+/// In JS a value is `null`, a number (a `BigInt` when an integer falls
+/// outside the safe integer range), a string or a `Uint8Array`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Reads column `i` of the current row of `stmt`, picking the variant
+    /// from `sqlite3_column_type`.
+    ///
+    /// # Safety
+    ///
+    /// `stmt` must be a valid statement positioned on a row.
+    pub(crate) unsafe fn from_column(stmt: *mut ffi::sqlite3_stmt, i: i32) -> Value {
+        match ffi::sqlite3_column_type(stmt, i) {
+            ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_column_int64(stmt, i)),
+            ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_column_double(stmt, i)),
+            ffi::SQLITE_TEXT => {
+                let text = ffi::sqlite3_column_text(stmt, i);
+                let len = ffi::sqlite3_column_bytes(stmt, i) as usize;
+                if text.is_null() || len == 0 {
+                    Value::Text(String::new())
+                } else {
+                    let bytes = std::slice::from_raw_parts(text, len);
+                    Value::Text(String::from_utf8_lossy(bytes).into_owned())
+                }
+            }
+            ffi::SQLITE_BLOB => {
+                let blob = ffi::sqlite3_column_blob(stmt, i) as *const u8;
+                let len = ffi::sqlite3_column_bytes(stmt, i) as usize;
+                if blob.is_null() || len == 0 {
+                    Value::Blob(Vec::new())
+                } else {
+                    Value::Blob(std::slice::from_raw_parts(blob, len).to_vec())
+                }
+            }
+            _ => Value::Null,
+        }
+    }
+
+    fn storage_class(&self) -> StorageClass {
+        match self {
+            Value::Null => StorageClass::Null,
+            Value::Integer(_) => StorageClass::Integer,
+            Value::Real(_) => StorageClass::Real,
+            Value::Text(_) => StorageClass::Text,
+            Value::Blob(_) => StorageClass::Blob,
+        }
+    }
+
+    /// Converts to the JS representation described on [`Value`].
+    pub fn to_js(&self) -> JsValue {
+        match self {
+            Value::Null => JsValue::NULL,
+            Value::Integer(v) if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(v) => {
+                JsValue::from_f64(*v as f64)
+            }
+            Value::Integer(v) => js_sys::BigInt::from(*v).into(),
+            Value::Real(v) => JsValue::from_f64(*v),
+            Value::Text(v) => JsValue::from_str(v),
+            Value::Blob(v) => js_sys::Uint8Array::from(v.as_slice()).into(),
+        }
+    }
+
+    /// Converts a JS value back into a SQLite value. Whole numbers become
+    /// integers and booleans become `0`/`1`.
+    pub fn from_js(value: &JsValue) -> Result<Value, Error> {
+        if value.is_null() || value.is_undefined() {
+            Ok(Value::Null)
+        } else if let Some(v) = value.as_bool() {
+            Ok(Value::Integer(v as i64))
+        } else if let Some(v) = value.as_f64() {
+            if v.fract() == 0.0 && v.abs() <= MAX_SAFE_INTEGER as f64 {
+                Ok(Value::Integer(v as i64))
+            } else {
+                Ok(Value::Real(v))
+            }
+        } else if value.is_bigint() {
+            i64::try_from(value.clone())
+                .map(Value::Integer)
+                .map_err(|_| {
+                    Error::new(
+                        ffi::SQLITE_MISMATCH,
+                        "BigInt out of range for a SQLite integer",
+                    )
+                })
+        } else if let Some(v) = value.as_string() {
+            Ok(Value::Text(v))
+        } else if let Some(v) = value.dyn_ref::<js_sys::Uint8Array>() {
+            Ok(Value::Blob(v.to_vec()))
+        } else if let Some(v) = value.dyn_ref::<js_sys::ArrayBuffer>() {
+            Ok(Value::Blob(js_sys::Uint8Array::new(v).to_vec()))
+        } else {
+            Err(Error::new(ffi::SQLITE_MISMATCH, "Unsupported SQLite value"))
+        }
+    }
+}
+
+/// The storage class a [`Value`] is tagged with when it leaves the worker.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StorageClass {
+    Null,
+    Integer,
+    Real,
+    Text,
+    Blob,
+}
+
+/// A [`Value`] tagged with its storage class, as `[class, value]`.
+///
+/// A JS number does not say whether it was a real or an integer, so a real
+/// such as `2.0` would otherwise come back as the integer `2`.
+struct Tagged<V>(V);
+
+impl Serialize for Tagged<&Value> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0.storage_class(), self.0).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tagged<Value> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tagged<Value>, D::Error> {
+        let (class, value) = <(StorageClass, Value)>::deserialize(deserializer)?;
+        match (class, value) {
+            (StorageClass::Real, Value::Integer(v)) => Ok(Tagged(Value::Real(v as f64))),
+            (class, value) if value.storage_class() == class => Ok(Tagged(value)),
+            (class, value) => Err(de::Error::custom(format!(
+                "expected a {:?} value, got {:?}",
+                class, value
+            ))),
+        }
+    }
+}
+
+/// (De)serializes result rows with every cell [`Tagged`], so they read back
+/// exactly as SQLite returned them, NULLs included.
+pub(crate) mod tagged_rows {
+    use super::{Tagged, Value};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct Row<'a>(&'a [Value]);
+
+    impl Serialize for Row<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.0.iter().map(Tagged))
+        }
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        rows: &[Vec<Value>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(rows.iter().map(|row| Row(row)))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<Value>>, D::Error> {
+        let rows = Vec::<Vec<Tagged<Value>>>::deserialize(deserializer)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|Tagged(value)| value).collect())
+            .collect())
+    }
+}
+
+/// Serializes an integer as a JS number, or as a `BigInt` outside the safe
+/// integer range.
+pub(crate) fn serialize_integer<S: Serializer>(v: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(v) {
+        serializer.serialize_i64(*v)
+    } else {
+        // serde_wasm_bindgen turns 128-bit integers into a BigInt
+        serializer.serialize_i128(*v as i128)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Integer(v) => serialize_integer(v, serializer),
+            Value::Real(v) => serializer.serialize_f64(*v),
+            Value::Text(v) => serializer.serialize_str(v),
+            Value::Blob(v) => serializer.serialize_bytes(v),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("null, a number, a string or bytes")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        i64::try_from(v)
+            .map(Value::Integer)
+            .map_err(|_| E::custom("integer out of range for a SQLite integer"))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Real(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::Text(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Blob(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Blob(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        Ok(Value::Blob(bytes))
+    }
+}
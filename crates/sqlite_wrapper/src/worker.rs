@@ -0,0 +1,522 @@
+use crate::{ffi, Database, Error, Migration, OpenOptions, Params, ResultSet, Scheduler, Vfs};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use wasm_bindgen::prelude::*;
+use web_sys::DedicatedWorkerGlobalScope;
+
+/// A [`WorkerRequest`] posted to the SQLite worker, tagged with an id that
+/// the worker's [`WorkerResponse`] echoes back.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerMessage {
+    pub id: u32,
+    pub request: WorkerRequest,
+}
+
+impl WorkerMessage {
+    /// Wraps `request` with a fresh id. Ids are unique within the page, so
+    /// every component posting to the same worker can tell replies apart.
+    pub fn new(request: WorkerRequest) -> WorkerMessage {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        WorkerMessage {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            request,
+        }
+    }
+
+    pub fn to_js(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(self).unwrap()
+    }
+}
+
+/// What the SQLite worker is asked to do.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum WorkerRequest {
+    /// Sets the database the worker opens on first use. Sent before any
+    /// other request; the worker posts no reply.
+    Configure {
+        filename: String,
+        #[serde(default)]
+        options: OpenOptions,
+    },
+    Execute {
+        sql: String,
+        #[serde(default)]
+        params: Params,
+        /// The transaction to run in, if any.
+        #[serde(default)]
+        txn: Option<String>,
+    },
+    Query {
+        sql: String,
+        #[serde(default)]
+        params: Params,
+        #[serde(default)]
+        txn: Option<String>,
+    },
+    /// Runs every statement in `sql`, answering with one result set each.
+    Script {
+        sql: String,
+        #[serde(default)]
+        txn: Option<String>,
+    },
+    /// Answers with an image of the whole database as a `Uint8Array`.
+    Export,
+    /// Replaces the whole database with `bytes`.
+    Import {
+        #[serde(with = "serde_bytes")]
+        bytes: Vec<u8>,
+    },
+    /// Brings the schema up to date, answering with the version reached.
+    Migrate {
+        migrations: Vec<Migration>,
+    },
+    /// Opens transaction `txn` on behalf of tab `owner`. Until it is
+    /// committed or rolled back, requests outside it wait their turn.
+    Begin {
+        txn: String,
+        owner: String,
+    },
+    Commit {
+        txn: String,
+    },
+    Rollback {
+        txn: String,
+    },
+    /// Rolls back any transaction left open by `owner`, which has gone away.
+    /// The worker posts no reply.
+    Abandon {
+        owner: String,
+    },
+}
+
+impl WorkerRequest {
+    /// The transaction this request belongs to, if any.
+    pub fn txn(&self) -> Option<&str> {
+        match self {
+            WorkerRequest::Execute { txn, .. }
+            | WorkerRequest::Query { txn, .. }
+            | WorkerRequest::Script { txn, .. } => txn.as_deref(),
+            WorkerRequest::Commit { txn } | WorkerRequest::Rollback { txn } => Some(txn),
+            WorkerRequest::Configure { .. }
+            | WorkerRequest::Export
+            | WorkerRequest::Import { .. }
+            | WorkerRequest::Migrate { .. }
+            | WorkerRequest::Begin { .. }
+            | WorkerRequest::Abandon { .. } => None,
+        }
+    }
+
+    /// The variant's name, for logging without the SQL, parameters or bytes
+    /// the request carries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WorkerRequest::Configure { .. } => "Configure",
+            WorkerRequest::Execute { .. } => "Execute",
+            WorkerRequest::Query { .. } => "Query",
+            WorkerRequest::Script { .. } => "Script",
+            WorkerRequest::Export => "Export",
+            WorkerRequest::Import { .. } => "Import",
+            WorkerRequest::Migrate { .. } => "Migrate",
+            WorkerRequest::Begin { .. } => "Begin",
+            WorkerRequest::Commit { .. } => "Commit",
+            WorkerRequest::Rollback { .. } => "Rollback",
+            WorkerRequest::Abandon { .. } => "Abandon",
+        }
+    }
+
+    /// Whether the worker answers this request. Fire-and-forget requests
+    /// must not produce a reply that a caller waiting on another request
+    /// could mistake for its own.
+    fn has_reply(&self) -> bool {
+        !matches!(
+            self,
+            WorkerRequest::Configure { .. } | WorkerRequest::Abandon { .. }
+        )
+    }
+}
+
+/// The database the worker opens on first use.
+struct Config {
+    filename: String,
+    options: OpenOptions,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            filename: "app.db".to_string(),
+            options: OpenOptions::default(),
+        }
+    }
+}
+
+/// The worker's answer to a [`WorkerRequest`].
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum WorkerReply {
+    Ok {
+        #[serde(with = "serde_wasm_bindgen::preserve")]
+        value: JsValue,
+    },
+    Err {
+        error: Error,
+    },
+}
+
+impl WorkerReply {
+    pub fn to_js(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(self).unwrap()
+    }
+
+    /// The successful value deserialized as `T`, or the error.
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<T, Error> {
+        match self {
+            WorkerReply::Ok { value } => serde_wasm_bindgen::from_value(value)
+                .map_err(|e| Error::new(ffi::SQLITE_ERROR, format!("Invalid worker reply: {}", e))),
+            WorkerReply::Err { error } => Err(error),
+        }
+    }
+}
+
+/// Reads a [`WorkerReply`] in its JS form, deserializing a successful value
+/// as `T`.
+pub fn parse_reply<T: DeserializeOwned>(reply: JsValue) -> Result<T, Error> {
+    serde_wasm_bindgen::from_value::<WorkerReply>(reply)
+        .map_err(|e| Error::new(ffi::SQLITE_ERROR, format!("Invalid worker reply: {}", e)))?
+        .into_result()
+}
+
+/// The worker's answer to the [`WorkerMessage`] with the same `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkerResponse {
+    /// `None` when the message was unreadable, so its id is unknown.
+    pub id: Option<u32>,
+    pub reply: WorkerReply,
+}
+
+impl WorkerResponse {
+    pub fn from_js(value: JsValue) -> Result<WorkerResponse, Error> {
+        serde_wasm_bindgen::from_value(value)
+            .map_err(|e| Error::new(ffi::SQLITE_ERROR, format!("Invalid worker response: {}", e)))
+    }
+
+    pub fn to_js(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(self).unwrap()
+    }
+
+    /// Buffers to transfer rather than copy when posting this response.
+    fn transfer(&self) -> js_sys::Array {
+        match &self.reply {
+            WorkerReply::Ok { value } => match value.dyn_ref::<js_sys::Uint8Array>() {
+                Some(bytes) => js_sys::Array::of1(&bytes.buffer()),
+                None => js_sys::Array::new(),
+            },
+            WorkerReply::Err { .. } => js_sys::Array::new(),
+        }
+    }
+}
+
+/// Runs a single worker message against the shared connection, opening it on
+/// first use.
+async fn handle_message(
+    db: &RefCell<Option<Database>>,
+    config: &RefCell<Config>,
+    scheduler: &RefCell<Scheduler>,
+    request: &WorkerRequest,
+) -> Result<JsValue, Error> {
+    if let WorkerRequest::Configure { filename, options } = request {
+        *config.borrow_mut() = Config {
+            filename: filename.clone(),
+            options: options.clone(),
+        };
+        return Ok(JsValue::UNDEFINED);
+    }
+
+    if db.borrow().is_none() {
+        let (filename, options) = {
+            let config = config.borrow();
+            (config.filename.clone(), config.options.clone())
+        };
+        *db.borrow_mut() = Some(open_when_released(&filename, &options).await?);
+    }
+
+    let db = db.borrow();
+    let db = db.as_ref().unwrap();
+
+    // Statements naming a transaction must run inside it, not on their own
+    if let Some(txn) = request.txn() {
+        if scheduler.borrow().session().map(|s| s.txn.as_str()) != Some(txn) {
+            let msg = format!("Transaction {} is not open", txn);
+            return Err(Error::new(ffi::SQLITE_MISUSE, msg));
+        }
+    }
+
+    let reply = match request {
+        WorkerRequest::Query { sql, params, .. } => {
+            serde_wasm_bindgen::to_value(&db.query(sql, params)?)
+        }
+        WorkerRequest::Execute { sql, params, .. } => {
+            serde_wasm_bindgen::to_value(&db.execute(sql, params)?)
+        }
+        WorkerRequest::Script { sql, .. } => serde_wasm_bindgen::to_value(&db.execute_script(sql)?),
+        WorkerRequest::Export => {
+            let bytes = db.export()?;
+            Ok(js_sys::Uint8Array::from(bytes.as_slice()).into())
+        }
+        WorkerRequest::Import { bytes } => {
+            db.import(bytes)?;
+            serde_wasm_bindgen::to_value(&ResultSet::default())
+        }
+        WorkerRequest::Migrate { migrations } => {
+            serde_wasm_bindgen::to_value(&db.migrate(migrations)?)
+        }
+        WorkerRequest::Begin { txn, owner } => {
+            db.begin()?;
+            scheduler
+                .borrow_mut()
+                .begin(txn.clone(), owner.clone(), js_sys::Date::now());
+            serde_wasm_bindgen::to_value(&ResultSet::default())
+        }
+        WorkerRequest::Commit { .. } => {
+            let committed = db.commit();
+            // A failed COMMIT can leave the transaction open for a retry
+            if !db.in_transaction() {
+                scheduler.borrow_mut().end();
+            }
+            committed?;
+            serde_wasm_bindgen::to_value(&ResultSet::default())
+        }
+        WorkerRequest::Rollback { .. } => {
+            let rolled_back = db.rollback();
+            scheduler.borrow_mut().end();
+            rolled_back?;
+            serde_wasm_bindgen::to_value(&ResultSet::default())
+        }
+        WorkerRequest::Configure { .. } => unreachable!(),
+        WorkerRequest::Abandon { owner } => {
+            let abandoned = scheduler
+                .borrow()
+                .session()
+                .is_some_and(|s| &s.owner == owner);
+            if abandoned {
+                web_sys::console::log_1(
+                    &format!("Rolling back transaction of departed tab {}", owner).into(),
+                );
+                let _ = db.rollback();
+                scheduler.borrow_mut().end();
+            }
+            Ok(JsValue::UNDEFINED)
+        }
+    };
+    reply.map_err(|e| Error::new(ffi::SQLITE_ERROR, e.to_string()))
+}
+
+/// How long a new leader waits for the previous leader's worker to release
+/// the OPFS sync access handles, and how often it tries in the meantime.
+const HANDOFF_TIMEOUT_MS: f64 = 10_000.0;
+const HANDOFF_RETRY_MS: i32 = 100;
+
+/// Opens the database, retrying while another worker still holds the OPFS
+/// access handles, e.g. a leader that is shutting down after a handoff or
+/// whose tab has just died.
+async fn open_when_released(filename: &str, options: &OpenOptions) -> Result<Database, Error> {
+    let deadline = js_sys::Date::now() + HANDOFF_TIMEOUT_MS;
+    loop {
+        match Database::open(filename, options).await {
+            Err(e)
+                if options.vfs == Vfs::Opfs
+                    && e.code == ffi::SQLITE_CANTOPEN
+                    && js_sys::Date::now() < deadline =>
+            {
+                web_sys::console::log_1(
+                    &format!("Waiting for the database to be released: {}", e).into(),
+                );
+                sleep(HANDOFF_RETRY_MS).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn sleep(ms: i32) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+        scope
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms)
+            .unwrap();
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+/// How often an idle transaction is checked on at most, e.g. while one of
+/// its statements is still running.
+const SESSION_CHECK_MS: f64 = 1000.0;
+
+/// What the worker's message handler and its timers share.
+struct State {
+    scope: DedicatedWorkerGlobalScope,
+    /// Opened lazily by the first message, so only the leader's worker ever
+    /// holds the OPFS access handles.
+    db: RefCell<Option<Database>>,
+    config: RefCell<Config>,
+    scheduler: RefCell<Scheduler>,
+    /// Set while messages are running. They run strictly in order, one at a
+    /// time, so that the first finishes opening the connection before the
+    /// next one runs.
+    draining: Cell<bool>,
+}
+
+impl State {
+    /// Runs queued messages until none may run, unless that is underway.
+    fn drain(self: &Rc<State>) {
+        if self.draining.replace(true) {
+            return;
+        }
+
+        let state = self.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            loop {
+                let next = state.scheduler.borrow_mut().next(js_sys::Date::now());
+                let Some(message) = next else {
+                    break;
+                };
+                web_sys::console::log_1(
+                    &format!(
+                        "Worker received #{}: {}",
+                        message.id,
+                        message.request.kind()
+                    )
+                    .into(),
+                );
+
+                let reply = match handle_message(
+                    &state.db,
+                    &state.config,
+                    &state.scheduler,
+                    &message.request,
+                )
+                .await
+                {
+                    Ok(value) => WorkerReply::Ok { value },
+                    Err(error) => WorkerReply::Err { error },
+                };
+
+                if let (WorkerRequest::Begin { .. }, WorkerReply::Ok { .. }) =
+                    (&message.request, &reply)
+                {
+                    state.watch_session();
+                }
+
+                if message.request.has_reply() {
+                    let response = WorkerResponse {
+                        id: Some(message.id),
+                        reply,
+                    };
+                    state
+                        .scope
+                        .post_message_with_transfer(&response.to_js(), &response.transfer())
+                        .unwrap();
+                }
+            }
+            state.draining.set(false);
+        });
+    }
+
+    /// Checks on the open transaction when it is due to time out.
+    fn watch_session(self: &Rc<State>) {
+        let scheduler = self.scheduler.borrow();
+        let (Some(session), Some(deadline)) = (scheduler.session(), scheduler.deadline()) else {
+            return;
+        };
+        let txn = session.txn.clone();
+        let delay = (deadline - js_sys::Date::now()).max(SESSION_CHECK_MS);
+
+        let state = self.clone();
+        let check = Closure::once_into_js(move || state.expire(&txn));
+        self.scope
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                check.unchecked_ref(),
+                delay as i32,
+            )
+            .unwrap();
+    }
+
+    /// Rolls back transaction `txn` if it is still open and has been idle
+    /// too long, letting the requests that waited for it run.
+    fn expire(self: &Rc<State>, txn: &str) {
+        if self.scheduler.borrow().session().map(|s| s.txn.as_str()) != Some(txn) {
+            return;
+        }
+        // One of its statements may be running
+        if self.draining.get() {
+            self.watch_session();
+            return;
+        }
+
+        let expired = self.scheduler.borrow_mut().expire(js_sys::Date::now());
+        let Some(session) = expired else {
+            self.watch_session();
+            return;
+        };
+        web_sys::console::warn_1(
+            &format!(
+                "Rolling back idle transaction {} of tab {}",
+                session.txn, session.owner
+            )
+            .into(),
+        );
+        if let Some(db) = self.db.borrow().as_ref() {
+            let _ = db.rollback();
+        }
+        self.drain();
+    }
+}
+
+#[wasm_bindgen]
+pub async fn main() -> Result<(), JsValue> {
+    web_sys::console::log_1(&JsValue::from_str("Setting up worker..."));
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+
+    let state = Rc::new(State {
+        scope: scope.clone(),
+        db: RefCell::new(None),
+        config: RefCell::new(Config::default()),
+        scheduler: RefCell::new(Scheduler::new()),
+        draining: Cell::new(false),
+    });
+
+    let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
+        let message = match serde_wasm_bindgen::from_value::<WorkerMessage>(e.data()) {
+            Ok(message) => message,
+            Err(err) => {
+                // Answer to the id if there is one, so the caller is not left waiting
+                let id = js_sys::Reflect::get(&e.data(), &"id".into())
+                    .ok()
+                    .and_then(|id| id.as_f64())
+                    .map(|id| id as u32);
+                let error = Error::new(
+                    ffi::SQLITE_MISUSE,
+                    format!("Invalid worker message: {}", err),
+                );
+                let response = WorkerResponse {
+                    id,
+                    reply: WorkerReply::Err { error },
+                };
+                state.scope.post_message(&response.to_js()).unwrap();
+                return;
+            }
+        };
+
+        state.scheduler.borrow_mut().push(message);
+        state.drain();
+    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+
+    Ok(())
+}
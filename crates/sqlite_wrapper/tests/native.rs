@@ -0,0 +1,215 @@
+//! `Database` on native SQLite, as built with the `native` feature.
+
+use futures::executor::block_on;
+use sqlite_wrapper::{ffi, Database, Migration, OpenOptions, Params, Value, Vfs};
+
+fn memory(name: &str) -> Database {
+    let options = OpenOptions {
+        vfs: Vfs::Memory,
+        ..OpenOptions::default()
+    };
+    block_on(Database::open(name, &options)).unwrap()
+}
+
+#[test]
+fn queries_return_typed_values() {
+    let db = memory("typed");
+    db.execute(
+        "CREATE TABLE t (i INTEGER, r REAL, s TEXT, b BLOB, n)",
+        &Params::None,
+    )
+    .unwrap();
+    let params = Params::Positional(vec![
+        Value::Integer(1),
+        Value::Real(1.5),
+        Value::Text("one".into()),
+        Value::Blob(vec![1, 2]),
+        Value::Null,
+    ]);
+    let inserted = db
+        .execute("INSERT INTO t VALUES (?, ?, ?, ?, ?)", &params)
+        .unwrap();
+    assert_eq!(inserted.rows_affected, 1);
+    assert_eq!(inserted.last_insert_rowid, 1);
+
+    let result = db.query("SELECT * FROM t", &Params::None).unwrap();
+    assert_eq!(result.columns, ["i", "r", "s", "b", "n"]);
+    assert_eq!(
+        result.rows,
+        [[
+            Value::Integer(1),
+            Value::Real(1.5),
+            Value::Text("one".into()),
+            Value::Blob(vec![1, 2]),
+            Value::Null,
+        ]]
+    );
+}
+
+#[test]
+fn named_parameters_bind_with_or_without_their_prefix() {
+    let db = memory("named");
+    let params = Params::Named(vec![
+        ("colon".into(), Value::Integer(1)),
+        ("@at".into(), Value::Text("two".into())),
+        ("dollar".into(), Value::Real(3.5)),
+    ]);
+    let result = db.query("SELECT :colon, @at, $dollar", &params).unwrap();
+    assert_eq!(
+        result.rows,
+        [[
+            Value::Integer(1),
+            Value::Text("two".into()),
+            Value::Real(3.5)
+        ]]
+    );
+}
+
+#[test]
+fn surplus_parameters_are_rejected() {
+    let db = memory("surplus");
+    let too_many = Params::Positional(vec![Value::Integer(1), Value::Integer(2)]);
+    let error = db.query("SELECT ?", &too_many).unwrap_err();
+    assert_eq!(error.code, ffi::SQLITE_RANGE);
+
+    let unknown = Params::Named(vec![("missing".into(), Value::Integer(1))]);
+    let error = db.query("SELECT :present", &unknown).unwrap_err();
+    assert_eq!(error.code, ffi::SQLITE_RANGE);
+    assert!(error.message.contains("missing"));
+}
+
+#[test]
+fn positional_parameters_must_all_be_given() {
+    let db = memory("positional");
+    let too_few = Params::Positional(vec![Value::Integer(1)]);
+    let error = db.query("SELECT ?, ?", &too_few).unwrap_err();
+    assert_eq!(error.code, ffi::SQLITE_RANGE);
+
+    let error = db.query("SELECT ?", &Params::None).unwrap_err();
+    assert_eq!(error.code, ffi::SQLITE_RANGE);
+}
+
+#[test]
+fn only_inserts_report_a_rowid() {
+    let db = memory("rowids");
+    db.execute("CREATE TABLE t (x)", &Params::None).unwrap();
+    let inserted = db
+        .execute("INSERT INTO t VALUES (1), (2)", &Params::None)
+        .unwrap();
+    assert_eq!(inserted.last_insert_rowid, 2);
+
+    let updated = db.execute("UPDATE t SET x = x + 1", &Params::None).unwrap();
+    assert_eq!(updated.rows_affected, 2);
+    assert_eq!(updated.last_insert_rowid, 0);
+
+    let results = db
+        .execute_script("SELECT * FROM t; INSERT INTO t VALUES (3); DELETE FROM t WHERE x = 2")
+        .unwrap();
+    let rowids: Vec<_> = results.iter().map(|r| r.last_insert_rowid).collect();
+    assert_eq!(rowids, [0, 3, 0]);
+}
+
+#[test]
+fn scripts_report_each_statement_and_where_they_fail() {
+    let db = memory("script");
+    let results = db
+        .execute_script(
+            "CREATE TABLE t (x);
+             INSERT INTO t VALUES (1), (2), (3);
+             SELECT x FROM t WHERE x > 1;
+             DELETE FROM t WHERE x = 1",
+        )
+        .unwrap();
+    let counts: Vec<_> = results
+        .iter()
+        .map(|r| (r.rows.len(), r.rows_affected))
+        .collect();
+    assert_eq!(counts, [(0, 0), (0, 3), (2, 0), (0, 1)]);
+
+    let sql = "SELECT 1; SELECT 2; SELEC 3; SELECT 4";
+    let error = db.execute_script(sql).unwrap_err();
+    assert_eq!(error.statement_index, Some(2));
+    assert_eq!(error.offset, sql.find("SELEC 3"));
+}
+
+#[test]
+fn failed_transactions_roll_back() {
+    let db = memory("transactions");
+    db.execute("CREATE TABLE t (x UNIQUE)", &Params::None)
+        .unwrap();
+
+    let error = db
+        .transaction(|db| {
+            db.execute("INSERT INTO t VALUES (1)", &Params::None)?;
+            db.execute("INSERT INTO t VALUES (1)", &Params::None)
+        })
+        .unwrap_err();
+    assert_eq!(error.code, ffi::SQLITE_CONSTRAINT);
+    assert!(!db.in_transaction());
+
+    let count = db.query("SELECT count(*) FROM t", &Params::None).unwrap();
+    assert_eq!(count.rows, [[Value::Integer(0)]]);
+}
+
+#[test]
+fn failed_commits_roll_back() {
+    let db = memory("failed_commit");
+    db.execute_script(
+        "PRAGMA foreign_keys = ON;
+         CREATE TABLE parent (id INTEGER PRIMARY KEY);
+         CREATE TABLE child (parent REFERENCES parent DEFERRABLE INITIALLY DEFERRED);",
+    )
+    .unwrap();
+
+    // The deferred foreign key is only checked by COMMIT
+    let error = db
+        .transaction(|db| db.execute("INSERT INTO child VALUES (1)", &Params::None))
+        .unwrap_err();
+    assert_eq!(error.code & 0xff, ffi::SQLITE_CONSTRAINT);
+    assert!(!db.in_transaction());
+
+    let count = db
+        .query("SELECT count(*) FROM child", &Params::None)
+        .unwrap();
+    assert_eq!(count.rows, [[Value::Integer(0)]]);
+}
+
+#[test]
+fn migrations_and_images_carry_over() {
+    let db = memory("source");
+    let version = db
+        .migrate(&[
+            Migration::new(1, "CREATE TABLE t (x)"),
+            Migration::new(2, "INSERT INTO t VALUES ('kept')"),
+        ])
+        .unwrap();
+    assert_eq!(version, 2);
+
+    let copy = memory("copy");
+    copy.import(&db.export().unwrap()).unwrap();
+    assert_eq!(copy.user_version().unwrap(), 2);
+    let rows = copy.query("SELECT x FROM t", &Params::None).unwrap();
+    assert_eq!(rows.rows, [[Value::Text("kept".into())]]);
+}
+
+#[test]
+fn files_persist_between_connections() {
+    let directory = std::env::temp_dir().join(format!("sqlite_wrapper-{}", std::process::id()));
+    let options = OpenOptions {
+        opfs_directory: Some(directory.to_string_lossy().into_owned()),
+        ..OpenOptions::default()
+    };
+
+    let mut db = block_on(Database::open("app.sqlite3", &options)).unwrap();
+    db.execute_script("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+        .unwrap();
+    db.close().unwrap();
+    assert!(directory.join("app.sqlite3").exists());
+
+    let db = block_on(Database::open("app.sqlite3", &options)).unwrap();
+    let rows = db.query("SELECT x FROM t", &Params::None).unwrap();
+    assert_eq!(rows.rows, [[Value::Integer(42)]]);
+
+    drop(db);
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,459 @@
+use crate::transport::Handler;
+use crate::{Election, TabMessage};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::rc::{Rc, Weak};
+use tab_coordinator_protocol::console::{error, log, warn};
+use tab_coordinator_protocol::{check_register, JsPayload, Transport, PROTOCOL_VERSION};
+use wasm_bindgen::prelude::*;
+use web_sys::BroadcastChannel;
+
+/// When another tab was last heard from and how long it may stay silent,
+/// in ms.
+struct Peer {
+    last_seen: f64,
+    timeout: f64,
+    /// Whether it has sent a heartbeat since registering. Until then it is
+    /// still hearing from the other tabs, so it does not lead yet.
+    settled: bool,
+}
+
+/// One of our routed requests that has not been answered yet.
+struct InFlight<P> {
+    request: P,
+    /// The request id it was last forwarded with. Every forward gets a new
+    /// one, so a former leader answering an earlier forward, e.g. with a
+    /// busy reply, is not mistaken for the answer.
+    forward_id: u32,
+}
+
+/// Coordinates tabs over a `BroadcastChannel` where `SharedWorker` is not
+/// available.
+///
+/// Every tab runs one and does for itself what the shared worker does for
+/// all tabs: it knows who leads, answers leader checks, carries requests to
+/// the leader and picks out the replies meant for this tab. Under lock
+/// election the tab holding the lock announces itself; otherwise every tab
+/// picks the live tab with the smallest id, so they agree without talking it
+/// over. A tab only takes part in that once it has sent its first heartbeat,
+/// by when every other tab has answered its `Register` with one of theirs,
+/// and announces when it takes over, since the others may have noticed
+/// first.
+/// Requests still waiting for a reply are sent again whenever the leader
+/// changes. Tabs speaking another protocol version are told so and left out.
+///
+/// What our tab posts goes to [`post`](BroadcastCoordinator::post), what
+/// the other tabs broadcast to [`receive`](BroadcastCoordinator::receive),
+/// and what is meant for our tab to the handler. `C` reaches every other
+/// tab, a `BroadcastChannel` in the browser. Times are ms on any clock that
+/// only moves forward, `Date.now()` in the browser.
+pub struct BroadcastCoordinator<P = JsPayload, C = BroadcastChannel> {
+    channel: C,
+    tab_id: String,
+    /// Our heartbeat timeout, which we tell tabs that register.
+    timeout_ms: Cell<u32>,
+    /// Whether we have sent a heartbeat since registering.
+    settled: Cell<bool>,
+    election: Cell<Election>,
+    leader: RefCell<Option<String>>,
+    peers: RefCell<BTreeMap<String, Peer>>,
+    /// Tabs we could not accept when they registered.
+    rejected: RefCell<HashSet<String>>,
+    /// Our routed requests that have not been answered, by the request id
+    /// our tab knows them by.
+    in_flight: RefCell<BTreeMap<u32, InFlight<P>>>,
+    next_forward_id: Cell<u32>,
+    handler: RefCell<Option<Handler<P>>>,
+}
+
+impl BroadcastCoordinator {
+    /// Joins the `BroadcastChannel` called `name` on behalf of `tab_id`.
+    pub(crate) fn open(name: &str, tab_id: &str) -> Result<Rc<BroadcastCoordinator>, JsValue> {
+        let coordinator = Rc::new(BroadcastCoordinator::new(
+            BroadcastChannel::new(name)?,
+            tab_id,
+        ));
+
+        let weak: Weak<BroadcastCoordinator> = Rc::downgrade(&coordinator);
+        let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
+            let Some(coordinator) = weak.upgrade() else {
+                return;
+            };
+            match serde_wasm_bindgen::from_value::<TabMessage>(e.data()) {
+                Ok(msg) => coordinator.receive(msg, js_sys::Date::now()),
+                // Most likely a tab from another version of the app
+                Err(err) => web_sys::console::error_3(
+                    &"Unreadable message from another tab:".into(),
+                    &err.into(),
+                    &e.data(),
+                ),
+            }
+        }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+        coordinator
+            .channel
+            .set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        onmessage.forget();
+
+        Ok(coordinator)
+    }
+}
+
+impl<P: Clone + Debug, C: Transport<P>> BroadcastCoordinator<P, C> {
+    /// Coordinates on behalf of `tab_id` with the tabs `channel` reaches.
+    pub fn new(channel: C, tab_id: &str) -> BroadcastCoordinator<P, C> {
+        BroadcastCoordinator {
+            channel,
+            tab_id: tab_id.to_string(),
+            timeout_ms: Cell::new(0),
+            settled: Cell::new(false),
+            election: Cell::new(Election::default()),
+            leader: RefCell::new(None),
+            peers: RefCell::new(BTreeMap::new()),
+            rejected: RefCell::new(HashSet::new()),
+            in_flight: RefCell::new(BTreeMap::new()),
+            next_forward_id: Cell::new(1),
+            handler: RefCell::new(None),
+        }
+    }
+
+    /// Starts passing what is meant for our tab to `handler`.
+    pub fn set_handler(&self, handler: Handler<P>) {
+        *self.handler.borrow_mut() = Some(handler);
+    }
+
+    /// Handles a message from our own tab.
+    pub fn post(&self, msg: TabMessage<P>, now: f64) -> Result<(), C::Error> {
+        match msg {
+            TabMessage::Register {
+                election,
+                timeout_ms,
+                ..
+            } => {
+                self.election.set(election);
+                self.timeout_ms.set(timeout_ms);
+                // The other tabs answer with a heartbeat; we elect once we
+                // have had a heartbeat interval to hear them
+                self.broadcast(&msg)?;
+            }
+            TabMessage::Heartbeat { .. } => {
+                self.broadcast(&msg)?;
+                self.settled.set(true);
+                self.sweep(now);
+                self.elect();
+            }
+            TabMessage::CheckLeader { request_id, .. } => {
+                self.deliver(TabMessage::LeaderResponse {
+                    is_leader: self.is_leader(),
+                    request_id,
+                });
+            }
+            TabMessage::QueryLeader { .. } => {
+                if self.is_leader() {
+                    self.deliver(msg);
+                } else {
+                    self.broadcast(&msg)?;
+                }
+            }
+            TabMessage::LeaderDataResponse {
+                ref from_tab_id, ..
+            } => {
+                if *from_tab_id == self.tab_id {
+                    self.deliver(msg);
+                } else {
+                    self.broadcast(&msg)?;
+                }
+            }
+            TabMessage::ExecuteQuery {
+                request,
+                request_id,
+                ..
+            } => {
+                let entry = InFlight {
+                    request,
+                    forward_id: 0,
+                };
+                self.in_flight.borrow_mut().insert(request_id, entry);
+                self.forward(request_id)?;
+            }
+            TabMessage::QueryResponse {
+                ref from_tab_id,
+                request_id,
+                ..
+            } => {
+                if *from_tab_id == self.tab_id {
+                    self.complete(request_id, msg);
+                } else {
+                    self.broadcast(&msg)?;
+                }
+            }
+            TabMessage::Disconnect { .. } => self.broadcast(&msg)?,
+            TabMessage::RequestLeadership { .. } => match self.election.get() {
+                // We got the lock
+                Election::Lock => {
+                    let leader_id = Some(self.tab_id.clone());
+                    self.broadcast(&TabMessage::LeaderChanged {
+                        leader_id: leader_id.clone(),
+                    })?;
+                    self.set_leader(leader_id);
+                }
+                Election::Coordinator => {
+                    warn("Moving leadership between tabs without SharedWorker needs Web Locks")
+                }
+            },
+            TabMessage::YieldLeadership { .. } => {
+                warn("Moving leadership between tabs without SharedWorker needs Web Locks")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles a message another tab broadcast.
+    pub fn receive(&self, msg: TabMessage<P>, now: f64) {
+        match msg {
+            TabMessage::Register {
+                tab_id,
+                timeout_ms,
+                protocol_version,
+                capabilities,
+                ..
+            } => {
+                if let Err(reason) = check_register(protocol_version, capabilities) {
+                    error(&format!("🚫 Rejecting tab {}: {}", tab_id, reason));
+                    let _ = self.broadcast(&TabMessage::Rejected {
+                        tab_id: tab_id.clone(),
+                        reason,
+                        protocol_version: PROTOCOL_VERSION,
+                    });
+                    self.rejected.borrow_mut().insert(tab_id);
+                    return;
+                }
+                self.touch(tab_id, timeout_ms, false, now);
+                // Let the newcomer know we are here before it elects
+                let _ = self.broadcast(&TabMessage::Heartbeat {
+                    tab_id: self.tab_id.clone(),
+                    timeout_ms: self.timeout_ms.get(),
+                });
+                // Tell the newcomer who leads
+                if self.is_leader() && self.election.get() == Election::Lock {
+                    let _ = self.broadcast(&TabMessage::LeaderChanged {
+                        leader_id: Some(self.tab_id.clone()),
+                    });
+                }
+            }
+            TabMessage::Heartbeat { tab_id, timeout_ms }
+                if !self.rejected.borrow().contains(&tab_id) =>
+            {
+                self.touch(tab_id, timeout_ms, true, now);
+                self.elect();
+            }
+            TabMessage::Rejected { ref tab_id, .. } if *tab_id == self.tab_id => self.deliver(msg),
+            TabMessage::QueryLeader { .. } | TabMessage::ExecuteQuery { .. }
+                if self.is_leader() =>
+            {
+                self.deliver(msg)
+            }
+            TabMessage::LeaderDataResponse {
+                ref from_tab_id, ..
+            } if *from_tab_id == self.tab_id => self.deliver(msg),
+            TabMessage::QueryResponse {
+                ref from_tab_id,
+                request_id,
+                ..
+            } if *from_tab_id == self.tab_id => self.complete(request_id, msg),
+            TabMessage::Disconnect { tab_id } => self.drop_peer(&tab_id),
+            TabMessage::LeaderChanged { leader_id }
+                if self.election.get() == Election::Lock
+                    && !leader_id
+                        .as_ref()
+                        .is_some_and(|id| self.rejected.borrow().contains(id)) =>
+            {
+                self.set_leader(leader_id)
+            }
+            // A tab we already took for the leader has just seen it that way
+            TabMessage::LeaderChanged {
+                leader_id: Some(ref leader_id),
+            } if self.election.get() == Election::Coordinator
+                && self.leader.borrow().as_ref() == Some(leader_id) =>
+            {
+                self.replay()
+            }
+            _ => {}
+        }
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.borrow().as_ref() == Some(&self.tab_id)
+    }
+
+    fn broadcast(&self, msg: &TabMessage<P>) -> Result<(), C::Error> {
+        self.channel.post(msg)
+    }
+
+    /// Passes a message to our own tab.
+    fn deliver(&self, msg: TabMessage<P>) {
+        let handler = self.handler.borrow().clone();
+        if let Some(handler) = handler {
+            handler(msg);
+        }
+    }
+
+    /// Sends one of our requests to the leader under a new forward id, or
+    /// holds it until there is one.
+    fn forward(&self, request_id: u32) -> Result<(), C::Error> {
+        if self.leader.borrow().is_none() {
+            return Ok(());
+        }
+        let forward_id = self.next_forward_id.get();
+        self.next_forward_id.set(forward_id.wrapping_add(1));
+        let request = {
+            let mut in_flight = self.in_flight.borrow_mut();
+            let Some(entry) = in_flight.get_mut(&request_id) else {
+                return Ok(());
+            };
+            entry.forward_id = forward_id;
+            entry.request.clone()
+        };
+
+        let msg = TabMessage::ExecuteQuery {
+            request,
+            from_tab_id: self.tab_id.clone(),
+            request_id: forward_id,
+        };
+        if self.is_leader() {
+            self.deliver(msg);
+        } else {
+            self.broadcast(&msg)?;
+        }
+        Ok(())
+    }
+
+    /// Delivers the reply to the latest forward of one of our requests, under
+    /// the id our tab knows it by. Replies to earlier forwards, e.g. from a
+    /// former leader finishing after a handoff, are dropped.
+    fn complete(&self, forward_id: u32, msg: TabMessage<P>) {
+        let TabMessage::QueryResponse { reply, .. } = msg else {
+            return;
+        };
+        let request_id = {
+            let mut in_flight = self.in_flight.borrow_mut();
+            let Some(request_id) = in_flight
+                .iter()
+                .find(|(_, entry)| entry.forward_id == forward_id)
+                .map(|(id, _)| *id)
+            else {
+                log("Ignoring stale query response");
+                return;
+            };
+            in_flight.remove(&request_id);
+            request_id
+        };
+        self.deliver(TabMessage::QueryResponse {
+            reply,
+            from_tab_id: self.tab_id.clone(),
+            request_id,
+        });
+    }
+
+    fn set_leader(&self, leader_id: Option<String>) {
+        if *self.leader.borrow() == leader_id {
+            return;
+        }
+        log(&format!("👑 Leader is now {:?}", leader_id));
+        *self.leader.borrow_mut() = leader_id.clone();
+        let announce = self.election.get() == Election::Coordinator && self.is_leader();
+        self.deliver(TabMessage::LeaderChanged { leader_id });
+        // The old leader may never answer
+        self.replay();
+
+        if announce {
+            // Tabs that noticed before we did may have sent us requests,
+            // which we dropped while we did not lead. They send them again
+            // when they hear this; one that did reach us after we took over
+            // then runs twice.
+            let msg = TabMessage::LeaderChanged {
+                leader_id: Some(self.tab_id.clone()),
+            };
+            if let Err(e) = self.broadcast(&msg) {
+                error(&format!("Could not announce leadership: {:?}", e));
+            }
+        }
+    }
+
+    /// Sends every unanswered request of ours to the leader again.
+    fn replay(&self) {
+        let requests: Vec<u32> = self.in_flight.borrow().keys().copied().collect();
+        for request_id in requests {
+            if let Err(e) = self.forward(request_id) {
+                error(&format!("Could not replay a request: {:?}", e));
+            }
+        }
+    }
+
+    /// Under deterministic election, the settled live tab with the smallest
+    /// id leads. Until we have settled ourselves we may not know every tab,
+    /// so we leave the leader alone.
+    fn elect(&self) {
+        if self.election.get() != Election::Coordinator || !self.settled.get() {
+            return;
+        }
+        let leader_id = self
+            .peers
+            .borrow()
+            .iter()
+            .filter(|(_, peer)| peer.settled)
+            .map(|(id, _)| id)
+            .chain(std::iter::once(&self.tab_id))
+            .min()
+            .cloned();
+        self.set_leader(leader_id);
+    }
+
+    /// Records that a tab is alive, and whether it has settled if it has not
+    /// before.
+    fn touch(&self, tab_id: String, timeout_ms: u32, settled: bool, now: f64) {
+        let mut peers = self.peers.borrow_mut();
+        let settled = settled || peers.get(&tab_id).is_some_and(|peer| peer.settled);
+        peers.insert(
+            tab_id,
+            Peer {
+                last_seen: now,
+                timeout: timeout_ms as f64,
+                settled,
+            },
+        );
+    }
+
+    /// Forgets tabs that stopped sending heartbeats.
+    fn sweep(&self, now: f64) {
+        let expired: Vec<String> = self
+            .peers
+            .borrow()
+            .iter()
+            .filter(|(_, peer)| now - peer.last_seen > peer.timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for tab_id in expired {
+            log(&format!("💀 Tab {} stopped responding", tab_id));
+            self.drop_peer(&tab_id);
+        }
+    }
+
+    fn drop_peer(&self, tab_id: &str) {
+        if self.peers.borrow_mut().remove(tab_id).is_none() {
+            return;
+        }
+        if self.is_leader() {
+            // Roll back anything the tab left open
+            self.deliver(TabMessage::TabLeft {
+                tab_id: tab_id.to_string(),
+            });
+        }
+        if self.leader.borrow().as_deref() == Some(tab_id) {
+            // Under lock election the next holder will announce itself
+            self.set_leader(None);
+        }
+        self.elect();
+    }
+}
@@ -0,0 +1,57 @@
+use wasm_bindgen::prelude::*;
+use web_sys::{console, Blob, BlobPropertyBag, Url, Worker};
+
+/// A worker that posts an empty message every `e.data` milliseconds.
+const TIMER: &str = "onmessage = (e) => setInterval(() => postMessage(null), e.data);";
+
+/// Calls `beat` every `interval_ms` for as long as the tab lives.
+///
+/// Chrome throttles timers in tabs hidden for five minutes to once a minute,
+/// far less often than a heartbeat is due, but not timers in workers. So the
+/// ticks come from a worker, and from the window only where one cannot be
+/// started, e.g. when a content security policy forbids `blob:` workers.
+pub(crate) fn start(interval_ms: u32, beat: Box<dyn FnMut()>) -> Result<(), JsValue> {
+    let beat = Closure::wrap(beat);
+    let callback: js_sys::Function = beat.as_ref().unchecked_ref::<js_sys::Function>().clone();
+    beat.forget();
+
+    match timer(interval_ms) {
+        Ok(worker) => {
+            worker.set_onmessage(Some(&callback));
+            let fallback = Closure::once_into_js(move |_: web_sys::Event| {
+                console::warn_1(&"Heartbeat worker failed, using a window timer".into());
+                let _ = window_timer(&callback, interval_ms);
+            });
+            worker.set_onerror(Some(fallback.unchecked_ref()));
+            // Runs as long as the tab, like the callback
+            std::mem::forget(worker);
+            Ok(())
+        }
+        Err(e) => {
+            console::warn_2(&"Could not start the heartbeat worker:".into(), &e);
+            window_timer(&callback, interval_ms)
+        }
+    }
+}
+
+fn timer(interval_ms: u32) -> Result<Worker, JsValue> {
+    let options = BlobPropertyBag::new();
+    options.set_type("text/javascript");
+    let source = js_sys::Array::of1(&TIMER.into());
+    let blob = Blob::new_with_str_sequence_and_options(&source, &options)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+
+    let worker = Worker::new(&url);
+    // The worker resolved the URL when it was constructed
+    Url::revoke_object_url(&url)?;
+    let worker = worker?;
+    worker.post_message(&interval_ms.into())?;
+    Ok(worker)
+}
+
+fn window_timer(callback: &js_sys::Function, interval_ms: u32) -> Result<(), JsValue> {
+    web_sys::window()
+        .unwrap()
+        .set_interval_with_callback_and_timeout_and_arguments_0(callback, interval_ms as i32)?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use wasm_bindgen::prelude::*;
+
+/// Who leads after a change, as seen from this tab.
+///
+/// In JS this is `{ isLeader, leaderId }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderChange {
+    /// Whether this tab is now the leader.
+    pub is_leader: bool,
+    /// The leading tab, `None` while no tab is registered.
+    pub leader_id: Option<String>,
+}
+
+/// Everyone listening for leadership changes on a tab.
+#[derive(Clone, Default)]
+pub(crate) struct LeaderListeners {
+    callback: Rc<RefCell<Option<js_sys::Function>>>,
+    streams: Rc<RefCell<Vec<mpsc::UnboundedSender<LeaderChange>>>>,
+}
+
+impl LeaderListeners {
+    pub(crate) fn set_callback(&self, callback: Option<js_sys::Function>) {
+        *self.callback.borrow_mut() = callback;
+    }
+
+    pub(crate) fn subscribe(&self) -> LeaderChanges {
+        let (sender, receiver) = mpsc::unbounded();
+        self.streams.borrow_mut().push(sender);
+        LeaderChanges {
+            receiver: RefCell::new(receiver),
+        }
+    }
+
+    pub(crate) fn notify(&self, change: LeaderChange) {
+        // Drop streams nobody is reading any more
+        self.streams
+            .borrow_mut()
+            .retain(|sender| sender.unbounded_send(change.clone()).is_ok());
+
+        let callback = self.callback.borrow().clone();
+        if let Some(callback) = callback {
+            let leader_id = change.leader_id.map(JsValue::from).unwrap_or(JsValue::NULL);
+            if let Err(e) = callback.call2(&JsValue::NULL, &change.is_leader.into(), &leader_id) {
+                web_sys::console::error_2(&"Leader change callback failed:".into(), &e);
+            }
+        }
+    }
+}
+
+/// A stream of [`LeaderChange`]s, from `leader_changes()`.
+///
+/// In Rust this is a [`Stream`]. In JS, `await changes.next()` resolves to
+/// the next change.
+#[wasm_bindgen]
+pub struct LeaderChanges {
+    receiver: RefCell<mpsc::UnboundedReceiver<LeaderChange>>,
+}
+
+#[wasm_bindgen]
+impl LeaderChanges {
+    #[wasm_bindgen(js_name = next)]
+    pub async fn next_js(&self) -> Result<JsValue, JsValue> {
+        let change = futures::future::poll_fn(|cx| self.receiver.borrow_mut().poll_next_unpin(cx));
+        match change.await {
+            Some(change) => Ok(serde_wasm_bindgen::to_value(&change)?),
+            None => Ok(JsValue::UNDEFINED),
+        }
+    }
+}
+
+impl Stream for LeaderChanges {
+    type Item = LeaderChange;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<LeaderChange>> {
+        self.receiver.borrow_mut().poll_next_unpin(cx)
+    }
+}
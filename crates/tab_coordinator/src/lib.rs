@@ -1,460 +1,368 @@
-use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use sqlite_wrapper::{
+    ffi, parse_reply, Error, OpenOptions, Params, QueryOptions, ResultSet, WorkerClient,
+    WorkerReply, WorkerRequest,
+};
 use std::rc::Rc;
+use tab_coordinator_protocol::JsPayload;
 use uuid::Uuid;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::{future_to_promise, JsFuture};
-use web_sys::{console, MessagePort, SharedWorker};
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-pub enum TabMessage {
-    Register {
-        tab_id: String,
-    },
-    CheckLeader {
-        tab_id: String,
-    },
-    LeaderResponse {
-        is_leader: bool,
-    },
-    QueryLeader {
-        from_tab_id: String,
-    },
-    LeaderDataResponse {
-        data: String,
-        from_tab_id: String,
-    },
-    ExecuteQuery {
-        sql: String,
-        from_tab_id: String,
-    },
-    QueryResponse {
-        results: Vec<Vec<String>>,
-        from_tab_id: String,
-        error: Option<String>,
-    },
-    Disconnect {
-        tab_id: String,
-    },
+use wasm_bindgen_futures::future_to_promise;
+use web_sys::{console, MessagePort};
+
+mod broadcast;
+mod heartbeat;
+mod leader;
+mod lock;
+mod tab;
+mod transport;
+
+pub use broadcast::BroadcastCoordinator;
+pub use leader::{LeaderChange, LeaderChanges};
+use lock::LockElection;
+pub use tab::{Executor, Tab};
+pub use tab_coordinator_protocol::{Election, TabMessage, Transport};
+use transport::Connection;
+pub use transport::Handler;
+
+/// How a [`TabManager`] reaches its coordinator.
+///
+/// In JS these are `{ sharedWorkerUrl, name, election, heartbeatIntervalMs,
+/// heartbeatTimeoutMs }`, all optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TabManagerOptions {
+    /// Script of the coordinating shared worker. Where `SharedWorker` is
+    /// missing or fails to start, tabs coordinate over a `BroadcastChannel`
+    /// instead, electing by lock when Web Locks are available.
+    pub shared_worker_url: String,
+    /// Name of the shared worker. Tabs only coordinate with tabs using the
+    /// same name, so each database should have its own.
+    pub name: Option<String>,
+    pub election: Election,
+    #[serde(flatten)]
+    pub heartbeat: HeartbeatOptions,
+}
+
+impl Default for TabManagerOptions {
+    fn default() -> TabManagerOptions {
+        TabManagerOptions {
+            shared_worker_url: "/pkg/worker/tab_coordinator_shared_worker.js".to_string(),
+            name: None,
+            election: Election::default(),
+            heartbeat: HeartbeatOptions::default(),
+        }
+    }
+}
+
+/// How a tab shows the coordinator it is alive. A tab that stays silent for
+/// longer than the timeout, because it crashed or was frozen, is dropped and,
+/// if it was the leader, the next tab takes over.
+///
+/// Heartbeats are timed by a worker, because Chrome throttles timers in a
+/// tab hidden for five minutes to once a minute. Where no worker can be
+/// started they fall back to a window timer, and tabs that should survive
+/// being hidden then need a timeout above a minute.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HeartbeatOptions {
+    pub heartbeat_interval_ms: u32,
+    pub heartbeat_timeout_ms: u32,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> HeartbeatOptions {
+        HeartbeatOptions {
+            heartbeat_interval_ms: 1000,
+            heartbeat_timeout_ms: 5000,
+        }
+    }
+}
+
+impl TabManagerOptions {
+    pub fn from_js(value: &JsValue) -> Result<TabManagerOptions, Error> {
+        if value.is_null() || value.is_undefined() {
+            return Ok(TabManagerOptions::default());
+        }
+        serde_wasm_bindgen::from_value(value.clone()).map_err(|e| {
+            Error::new(
+                ffi::SQLITE_MISUSE,
+                format!("Invalid tab manager options: {}", e),
+            )
+        })
+    }
 }
 
 #[wasm_bindgen]
 pub struct TabManager {
-    port: MessagePort,
-    tab_id: String,
-    leader_data: Rc<RefCell<String>>,
-    response_sender: Rc<RefCell<Option<oneshot::Sender<String>>>>,
-    leader_callback: Rc<RefCell<Option<js_sys::Function>>>,
-    query_response_sender: Rc<RefCell<Option<oneshot::Sender<Result<Vec<Vec<String>>, String>>>>>,
-    worker: Rc<web_sys::Worker>,
+    tab: Tab<JsPayload, Connection, WorkerClient>,
+    /// Set when leadership follows a Web Lock.
+    lock: Option<LockElection>,
 }
 
 #[wasm_bindgen]
 impl TabManager {
+    /// Starts this tab's SQLite worker from `sqlite_worker_url` and joins the
+    /// coordinator on behalf of this tab. `options` is optional; see
+    /// [`TabManagerOptions`]. When `name` is set the worker opens the
+    /// database of that name, otherwise `app.db`.
+    ///
+    /// The manager starts the worker itself because handing leadership over
+    /// means restarting it, which needs its script.
     #[wasm_bindgen(constructor)]
-    pub fn new(worker: web_sys::Worker) -> Result<TabManager, JsValue> {
-        let tab_id = Uuid::new_v4().to_string();
-        let leader_data = Rc::new(RefCell::new(String::new()));
-        let response_sender = Rc::new(RefCell::new(None::<oneshot::Sender<String>>));
-        let leader_callback = Rc::new(RefCell::new(None::<js_sys::Function>));
-        let query_response_sender = Rc::new(RefCell::new(
-            None::<oneshot::Sender<Result<Vec<Vec<String>>, String>>>,
-        ));
+    pub fn new(sqlite_worker_url: &str, options: JsValue) -> Result<TabManager, JsValue> {
+        let options = TabManagerOptions::from_js(&options)?;
+        let worker = WorkerClient::spawn(sqlite_worker_url)?;
+        if let Some(name) = &options.name {
+            worker.notify(WorkerRequest::Configure {
+                filename: name.clone(),
+                options: OpenOptions::default(),
+            })?;
+        }
+        TabManager::with_options(worker, &options)
+    }
+}
 
-        // Create the shared worker
-        let shared_worker = SharedWorker::new("/pkg/worker/tab_coordinator_shared_worker.js")?;
-        let port = shared_worker.port();
-        port.start();
+impl TabManager {
+    /// Joins the coordinator on behalf of this tab, running routed requests
+    /// on `worker` while it leads. Start `worker` with
+    /// [`WorkerClient::spawn`], since handing leadership over restarts it.
+    pub fn with_options(
+        worker: WorkerClient,
+        options: &TabManagerOptions,
+    ) -> Result<TabManager, JsValue> {
+        let tab_id = Uuid::new_v4().to_string();
 
-        // Use the provided SQLite worker
-        let worker = Rc::new(worker);
+        // Reach the coordinator, through the shared worker if we can
+        let connection = Connection::connect(options, &tab_id)?;
+        let tab = Tab::new(tab_id, connection.clone(), worker);
 
         // Set up message handler
-        let port_clone = port.clone();
-        let leader_data_clone = leader_data.clone();
-        let tab_id_clone = tab_id.clone();
-        let response_sender_clone = response_sender.clone();
-        let leader_callback_clone = leader_callback.clone();
-        let query_response_sender_clone = query_response_sender.clone();
-        let query_response_sender_closure = query_response_sender_clone.clone();
-
-        let port_message_handler = {
-            // Create a struct to hold our shared state
-            struct SharedState {
-                response_sender: Rc<RefCell<Option<oneshot::Sender<String>>>>,
-                leader_data: Rc<RefCell<String>>,
-                port: MessagePort,
-                tab_id: String,
-                worker: Rc<web_sys::Worker>,
-                query_response_sender:
-                    Rc<RefCell<Option<oneshot::Sender<Result<Vec<Vec<String>>, String>>>>>,
+        let tab_clone = tab.clone();
+        let handler: Handler = Rc::new(move |msg: TabMessage| {
+            if let Some(task) = tab_clone.handle(msg) {
+                wasm_bindgen_futures::spawn_local(task);
             }
-
-            let state = Rc::new(RefCell::new(SharedState {
-                response_sender: response_sender_clone,
-                leader_data: leader_data_clone,
-                port: port_clone,
-                tab_id: tab_id_clone,
-                worker: worker.clone(),
-                query_response_sender: query_response_sender_clone,
-            }));
-
-            Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
-                if let Ok(msg) = serde_wasm_bindgen::from_value::<TabMessage>(e.data()) {
-                    web_sys::console::log_1(&JsValue::from_str(&format!("Tab message: {:?}", msg)));
-
-                    match msg {
-                        TabMessage::LeaderResponse { is_leader } => {
-                            let sender = {
-                                let state = state.borrow();
-                                let sender = state.response_sender.borrow_mut().take();
-                                drop(state);
-                                sender
-                            };
-                            if let Some(sender) = sender {
-                                let _ = sender.send(is_leader.to_string());
-                            }
-                        }
-                        TabMessage::QueryLeader { from_tab_id } => {
-                            console::log_1(&JsValue::from_str("QueryLeader received by tab"));
-                            let data = {
-                                let state = state.borrow();
-                                let data = state.leader_data.borrow().clone();
-                                drop(state);
-                                data
-                            };
-                            let response = TabMessage::LeaderDataResponse { data, from_tab_id };
-                            let port = {
-                                let state = state.borrow();
-                                let port = state.port.clone();
-                                drop(state);
-                                port
-                            };
-                            port.post_message(&serde_wasm_bindgen::to_value(&response).unwrap())
-                                .unwrap();
-                        }
-                        TabMessage::ExecuteQuery { sql, from_tab_id } => {
-                            console::log_1(&JsValue::from_str("ExecuteQuery received by tab"));
-
-                            // Clone everything we need from state
-                            let (port, tab_id, worker, response_sender, query_response_sender) = {
-                                let state = state.borrow();
-                                (
-                                    state.port.clone(),
-                                    state.tab_id.clone(),
-                                    state.worker.clone(),
-                                    state.response_sender.clone(),
-                                    state.query_response_sender.clone(),
-                                )
-                            };
-                            let original_requester = from_tab_id.clone();
-
-                            wasm_bindgen_futures::spawn_local(async move {
-                                // Create a separate channel for leader check
-                                let (leader_sender, leader_receiver) = oneshot::channel::<String>();
-                                let msg = TabMessage::CheckLeader {
-                                    tab_id: tab_id.clone(),
-                                };
-
-                                // Store the sender in response_sender
-                                *response_sender.borrow_mut() = Some(leader_sender);
-
-                                port.post_message(&serde_wasm_bindgen::to_value(&msg).unwrap())
-                                    .unwrap();
-
-                                let is_leader = leader_receiver
-                                    .await
-                                    .map_err(|_| "Channel closed".to_string())
-                                    .unwrap()
-                                    == "true";
-
-                                if !is_leader {
-                                    let response = TabMessage::QueryResponse {
-                                        results: vec![],
-                                        from_tab_id: original_requester.clone(),
-                                        error: Some("Only leader can execute queries".to_string()),
-                                    };
-                                    port.post_message(
-                                        &serde_wasm_bindgen::to_value(&response).unwrap(),
-                                    )
-                                    .unwrap();
-
-                                    // Only send through query_response_sender if we're the original requester
-                                    if tab_id == original_requester {
-                                        if let Some(sender) =
-                                            query_response_sender.borrow_mut().take()
-                                        {
-                                            let _ =
-                                                sender
-                                                    .send(Err("Only leader can execute queries"
-                                                        .to_string()));
-                                        }
-                                    }
-                                    return;
-                                }
-
-                                // We are the leader, execute the query in our SQLite worker
-                                let promise = js_sys::Promise::new(&mut |resolve, _reject| {
-                                    let handler = move |e: web_sys::MessageEvent| {
-                                        resolve.call1(&JsValue::NULL, &e.data()).unwrap();
-                                    };
-                                    let closure = Closure::once(handler);
-                                    worker.set_onmessage(Some(closure.as_ref().unchecked_ref()));
-                                    worker
-                                        .post_message(&JsValue::from_str(&format!("QUERY:{}", sql)))
-                                        .unwrap();
-                                    closure.forget();
-                                });
-
-                                match JsFuture::from(promise).await {
-                                    Ok(result) => {
-                                        // Parse the result array from SQLite worker
-                                        let results = js_sys::Array::from(&result);
-                                        let mut parsed_results = Vec::new();
-
-                                        for i in 0..results.length() {
-                                            let row = results.get(i);
-                                            let row_array = js_sys::Array::from(&row);
-                                            let mut parsed_row = Vec::new();
-
-                                            for j in 0..row_array.length() {
-                                                let cell = row_array.get(j);
-                                                if !cell.is_undefined() && !cell.is_null() {
-                                                    parsed_row
-                                                        .push(cell.as_string().unwrap_or_default());
-                                                }
-                                            }
-
-                                            parsed_results.push(parsed_row);
-                                        }
-
-                                        // Send results through both channels
-                                        // 1. Back to the original requester through the shared worker
-                                        let response = TabMessage::QueryResponse {
-                                            results: parsed_results.clone(),
-                                            from_tab_id: original_requester.clone(),
-                                            error: None,
-                                        };
-                                        port.post_message(
-                                            &serde_wasm_bindgen::to_value(&response).unwrap(),
-                                        )
-                                        .unwrap();
-
-                                        // 2. If we're the leader AND the original requester, send through our query_response_sender
-                                        if tab_id == original_requester {
-                                            if let Some(sender) =
-                                                query_response_sender.borrow_mut().take()
-                                            {
-                                                let _ = sender.send(Ok(parsed_results));
-                                            }
-                                        }
-
-                                        console::log_1(&JsValue::from_str(&format!(
-                                            "Sent query response to tab: {}",
-                                            original_requester
-                                        )));
-                                    }
-                                    Err(e) => {
-                                        let error_msg = format!("Query error: {:?}", e);
-
-                                        // Send error through both channels
-                                        // 1. Back to the original requester through the shared worker
-                                        let response = TabMessage::QueryResponse {
-                                            results: vec![],
-                                            from_tab_id: original_requester.clone(),
-                                            error: Some(error_msg.clone()),
-                                        };
-                                        port.post_message(
-                                            &serde_wasm_bindgen::to_value(&response).unwrap(),
-                                        )
-                                        .unwrap();
-
-                                        // 2. If we're the leader AND the original requester, send through our query_response_sender
-                                        if tab_id == original_requester {
-                                            if let Some(sender) =
-                                                query_response_sender.borrow_mut().take()
-                                            {
-                                                let _ = sender.send(Err(error_msg));
-                                            }
-                                        }
-                                    }
-                                }
-                            });
-                        }
-                        TabMessage::QueryResponse {
-                            results,
-                            error,
-                            from_tab_id,
-                        } => {
-                            console::log_1(&JsValue::from_str(&format!(
-                                "Received query response for tab: {}",
-                                from_tab_id
-                            )));
-
-                            // First get the current tab ID
-                            let current_tab_id = {
-                                let state = state.borrow();
-                                state.tab_id.clone()
-                            };
-
-                            // Only process if we're the original requester
-                            if current_tab_id == from_tab_id {
-                                let sender = query_response_sender_closure.borrow_mut().take();
-
-                                if let Some(s) = sender {
-                                    let result = match error {
-                                        Some(err) => Err(err),
-                                        None => Ok(results),
-                                    };
-                                    let _ = s.send(result);
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }) as Box<dyn FnMut(web_sys::MessageEvent)>)
-        };
-
-        port.set_onmessage(Some(port_message_handler.as_ref().unchecked_ref()));
-        port_message_handler.forget();
+        });
+        connection.set_handler(handler);
 
         // Set up disconnect handler
-        let port_clone = port.clone();
-        let tab_id_clone = tab_id.clone();
+        let tab_clone = tab.clone();
         let onbeforeunload =
             wasm_bindgen::closure::Closure::wrap(Box::new(move |_: web_sys::Event| {
-                let msg = TabMessage::Disconnect {
-                    tab_id: tab_id_clone.clone(),
-                };
-                port_clone
-                    .post_message(&serde_wasm_bindgen::to_value(&msg).unwrap())
-                    .unwrap();
+                tab_clone.disconnect().unwrap();
             }) as Box<dyn FnMut(web_sys::Event)>);
-
         web_sys::window()
             .unwrap()
             .set_onbeforeunload(Some(onbeforeunload.as_ref().unchecked_ref()));
         onbeforeunload.forget();
 
+        // Without a shared worker the tabs cannot agree on a queue, so they
+        // follow a lock when the browser has Web Locks and otherwise all pick
+        // the same tab by id
+        let election = match &connection {
+            Connection::Broadcast(_) if lock::available() => Election::Lock,
+            Connection::Broadcast(_) => Election::Coordinator,
+            Connection::SharedWorker(_) => options.election,
+        };
+
         // Register this tab
-        let register_msg = TabMessage::Register {
-            tab_id: tab_id.clone(),
+        let timeout_ms = options.heartbeat.heartbeat_timeout_ms;
+        tab.register(timeout_ms, election)?;
+
+        // Queue for the lock; whoever holds it claims leadership
+        let lock = match election {
+            Election::Coordinator => None,
+            Election::Lock => {
+                let tab = tab.clone();
+                let name = format!(
+                    "tab_coordinator:{}",
+                    options.name.as_deref().unwrap_or_default()
+                );
+                Some(LockElection::start(
+                    name,
+                    Box::new(move || {
+                        let _ = tab.request_leadership();
+                    }),
+                )?)
+            }
         };
-        port.post_message(&serde_wasm_bindgen::to_value(&register_msg).unwrap())
-            .unwrap();
 
-        Ok(TabManager {
-            port,
-            tab_id,
-            leader_data,
-            response_sender,
-            leader_callback,
-            query_response_sender,
-            worker,
-        })
+        // A claim from a former holder can reach the coordinator after ours,
+        // e.g. when it stole the lock and closed right away, so while we hold
+        // the lock we claim it again whenever someone else is said to lead
+        if let Some(lock) = &lock {
+            let lock = lock.clone();
+            let tab_clone = tab.clone();
+            let mut changes = tab.leader_changes();
+            wasm_bindgen_futures::spawn_local(async move {
+                while let Some(change) = changes.next().await {
+                    if !change.is_leader && lock.is_held() {
+                        let _ = tab_clone.request_leadership();
+                    }
+                }
+            });
+        }
+
+        // Keep telling the coordinator we are alive
+        let tab_clone = tab.clone();
+        heartbeat::start(
+            options.heartbeat.heartbeat_interval_ms,
+            Box::new(move || {
+                let _ = tab_clone.heartbeat(timeout_ms);
+            }),
+        )?;
+
+        Ok(TabManager { tab, lock })
     }
+}
 
+#[wasm_bindgen]
+impl TabManager {
     #[wasm_bindgen]
     pub async fn check_leader(&self) -> Result<bool, JsValue> {
-        // Create a new channel specifically for this check_leader call
-        let (sender, receiver) = oneshot::channel();
-        *self.response_sender.borrow_mut() = Some(sender);
-
-        let msg = TabMessage::CheckLeader {
-            tab_id: self.tab_id.clone(),
-        };
-
-        self.port
-            .post_message(&serde_wasm_bindgen::to_value(&msg)?)?;
-
-        // Wait for response
-        let response = receiver
-            .await
-            .map_err(|_| JsValue::from_str("Channel closed"))?;
-
-        Ok(response == "true")
+        Ok(self.tab.check_leader().await?)
     }
 
     #[wasm_bindgen]
     pub fn query_leader(&self) -> js_sys::Promise {
-        let (sender, receiver) = oneshot::channel();
-        *self.response_sender.borrow_mut() = Some(sender);
+        let data = self.tab.query_leader();
+        future_to_promise(async move { Ok(JsValue::from_str(&data.await?)) })
+    }
 
-        let msg = TabMessage::QueryLeader {
-            from_tab_id: self.tab_id.clone(),
-        };
+    /// Calls `callback(isLeader, leaderId)` whenever leadership changes,
+    /// including once when this tab joins. Replaces any earlier callback;
+    /// pass `null` to remove it.
+    pub fn on_leader_change(&self, callback: Option<js_sys::Function>) {
+        self.tab.leader_listeners().set_callback(callback);
+    }
 
-        self.port
-            .post_message(&serde_wasm_bindgen::to_value(&msg).unwrap())
-            .unwrap();
+    /// A stream of leadership changes from now on. See [`LeaderChanges`].
+    pub fn leader_changes(&self) -> LeaderChanges {
+        self.tab.leader_changes()
+    }
 
-        future_to_promise(async move {
-            let data = receiver.await.unwrap();
-            Ok(JsValue::from_str(&data))
-        })
+    /// Asks the coordinator to make this tab the leader, e.g. because the
+    /// user is working in it. The current leader releases the database and
+    /// requests it had not answered are replayed here, as on failover. With
+    /// lock election this steals the lock.
+    pub fn request_leadership(&self) -> Result<(), JsValue> {
+        if let Some(lock) = &self.lock {
+            return lock.steal();
+        }
+        self.tab.request_leadership()
+    }
+
+    /// Asks the coordinator to hand leadership to the next tab, e.g. before
+    /// this one does heavy work. Does nothing if no other tab is open.
+    pub fn yield_leadership(&self) -> Result<(), JsValue> {
+        if let Some(lock) = &self.lock {
+            return lock.yield_lock();
+        }
+        self.tab.yield_leadership()
     }
 
     #[wasm_bindgen]
     pub fn get_tab_id(&self) -> String {
-        self.tab_id.clone()
+        self.tab.tab_id().to_string()
     }
 
     #[wasm_bindgen]
     pub fn save_data(&mut self, data: String) {
-        *self.leader_data.borrow_mut() = data;
+        self.tab.set_data(data);
     }
 
     #[wasm_bindgen]
     pub fn get_data(&self) -> String {
-        self.leader_data.borrow().clone()
+        self.tab.data()
     }
 
     #[wasm_bindgen]
-    pub fn send_leader_response(&self, from_tab_id: String) {
-        let msg = TabMessage::LeaderDataResponse {
-            data: self.leader_data.borrow().clone(),
-            from_tab_id,
-        };
-        self.port
-            .post_message(&serde_wasm_bindgen::to_value(&msg).unwrap())
+    pub fn send_leader_response(&self, from_tab_id: String, request_id: u32) {
+        self.tab
+            .send_leader_response(from_tab_id, request_id)
             .unwrap();
     }
 
+    /// The shared worker port, or `undefined` when tabs coordinate over a
+    /// `BroadcastChannel`.
     #[wasm_bindgen]
-    pub fn port(&self) -> MessagePort {
-        self.port.clone()
+    pub fn port(&self) -> Option<MessagePort> {
+        self.tab.transport().port()
     }
 
-    pub async fn route_query(&self, sql: &str) -> Result<JsValue, JsValue> {
-        // Create a new channel for this query
-        let (sender, receiver) = oneshot::channel();
+    pub async fn route_query(
+        &self,
+        sql: &str,
+        params: JsValue,
+        options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let options = QueryOptions::from_js(&options)?;
+        let request = WorkerRequest::Query {
+            sql: sql.to_string(),
+            params: Params::from_js(&params)?,
+            txn: None,
+        };
 
-        // Store the sender in query_response_sender
-        {
-            let mut query_sender = self.query_response_sender.borrow_mut();
-            *query_sender = Some(sender);
-        } // ensure the borrow is dropped
+        let result: ResultSet = self.route_request(request).await?;
+        Ok(result.to_js(options.row_mode))
+    }
+}
 
-        // Send the query request
-        let msg = TabMessage::ExecuteQuery {
-            sql: sql.to_string(),
-            from_tab_id: self.tab_id.clone(),
+impl TabManager {
+    /// Sends a request to the leader's SQLite worker through the coordinator
+    /// and waits for its result.
+    pub async fn route_request<T: DeserializeOwned>(
+        &self,
+        request: WorkerRequest,
+    ) -> Result<T, Error> {
+        let request = serde_wasm_bindgen::to_value(&request)
+            .map_err(|e| Error::new(ffi::SQLITE_MISUSE, format!("Invalid request: {}", e)))?;
+        let reply = self.tab.route(JsPayload(request)).await?;
+        parse_reply(reply.0)
+    }
+}
+
+/// The tab's SQLite worker runs what is routed to it while it leads.
+impl Executor<JsPayload> for WorkerClient {
+    fn execute(&self, request: JsPayload) -> LocalBoxFuture<'static, JsPayload> {
+        let worker = self.clone();
+        Box::pin(async move {
+            let reply = match serde_wasm_bindgen::from_value::<WorkerRequest>(request.0) {
+                // Parameters are bound in the worker, never spliced into the SQL
+                Ok(request) => worker.request(request).await,
+                Err(e) => {
+                    let error =
+                        Error::new(ffi::SQLITE_MISUSE, format!("Invalid routed request: {}", e));
+                    WorkerReply::Err { error }
+                }
+            };
+            JsPayload(reply.to_js())
+        })
+    }
+
+    fn busy(&self) -> JsPayload {
+        let error = Error::new(ffi::SQLITE_BUSY, "Only leader can execute queries");
+        JsPayload(WorkerReply::Err { error }.to_js())
+    }
+
+    /// The worker holds the OPFS access handles until it is gone, so only a
+    /// client started from a URL can hand the database over.
+    fn release(&self) {
+        if let Err(e) = self.restart() {
+            console::error_2(&"Could not release the database:".into(), &e);
+        }
+    }
+
+    fn abandon(&self, tab_id: &str) {
+        let request = WorkerRequest::Abandon {
+            owner: tab_id.to_string(),
         };
-        self.port
-            .post_message(&serde_wasm_bindgen::to_value(&msg)?)?;
-
-        // Wait for response
-        let response = receiver
-            .await
-            .map_err(|_| JsValue::from_str("Channel closed"))?;
-
-        // Convert the response to JsValue
-        match response {
-            Ok(results) => Ok(serde_wasm_bindgen::to_value(&results)?),
-            Err(err) => Err(JsValue::from_str(&err)),
+        if let Err(e) = self.notify(request) {
+            console::error_2(&"Could not roll back for a departed tab:".into(), &e);
         }
     }
 }
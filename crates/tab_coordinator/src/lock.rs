@@ -0,0 +1,155 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use web_sys::AbortController;
+
+#[wasm_bindgen]
+extern "C" {
+    /// `navigator.locks`. Bound by hand because web-sys only exposes it
+    /// behind `web_sys_unstable_apis`.
+    type LockManager;
+
+    #[wasm_bindgen(method, js_name = request)]
+    fn request_with_options(
+        this: &LockManager,
+        name: &str,
+        options: &js_sys::Object,
+        callback: &js_sys::Function,
+    ) -> js_sys::Promise;
+}
+
+/// Whether the browser has the Web Locks API.
+pub(crate) fn available() -> bool {
+    let navigator = web_sys::window().unwrap().navigator();
+    js_sys::Reflect::get(&navigator, &"locks".into()).is_ok_and(|locks| !locks.is_undefined())
+}
+
+/// Leader election with the Web Locks API: whichever tab holds an exclusive
+/// lock leads, and the others queue for it. The browser releases the lock
+/// when the holding tab dies, so the next tab in line takes over even if
+/// nothing told the coordinator.
+#[derive(Clone)]
+pub(crate) struct LockElection {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    locks: LockManager,
+    name: String,
+    /// Called whenever this tab acquires the lock.
+    on_acquired: Box<dyn Fn()>,
+    /// Bumped by every request, so callbacks of superseded requests know to
+    /// let go.
+    generation: Cell<u32>,
+    /// Resolves the promise that keeps the lock held.
+    release: RefCell<Option<js_sys::Function>>,
+    /// Aborts our place in the queue while we are waiting.
+    waiting: RefCell<Option<AbortController>>,
+}
+
+impl LockElection {
+    /// Joins the queue for the lock `name`.
+    pub(crate) fn start(name: String, on_acquired: Box<dyn Fn()>) -> Result<LockElection, JsValue> {
+        let navigator = web_sys::window().unwrap().navigator();
+        let locks = js_sys::Reflect::get(&navigator, &"locks".into())?;
+        if locks.is_undefined() {
+            return Err(JsValue::from_str("Web Locks are not available"));
+        }
+
+        let election = LockElection {
+            inner: Rc::new(Inner {
+                locks: locks.unchecked_into(),
+                name,
+                on_acquired,
+                generation: Cell::new(0),
+                release: RefCell::new(None),
+                waiting: RefCell::new(None),
+            }),
+        };
+        election.acquire(false)?;
+        Ok(election)
+    }
+
+    pub(crate) fn is_held(&self) -> bool {
+        self.inner.release.borrow().is_some()
+    }
+
+    /// Takes the lock from whoever holds it.
+    pub(crate) fn steal(&self) -> Result<(), JsValue> {
+        if self.is_held() {
+            return Ok(());
+        }
+        self.acquire(true)
+    }
+
+    /// Lets go of the lock and queues for it again behind the other tabs.
+    pub(crate) fn yield_lock(&self) -> Result<(), JsValue> {
+        let Some(release) = self.inner.release.borrow_mut().take() else {
+            return Ok(());
+        };
+        release.call0(&JsValue::NULL)?;
+        self.acquire(false)
+    }
+
+    fn acquire(&self, steal: bool) -> Result<(), JsValue> {
+        let inner = &self.inner;
+        let generation = inner.generation.get().wrapping_add(1);
+        inner.generation.set(generation);
+
+        // Give up any place we already have in the queue
+        if let Some(controller) = inner.waiting.borrow_mut().take() {
+            controller.abort();
+        }
+
+        let options = js_sys::Object::new();
+        js_sys::Reflect::set(&options, &"mode".into(), &"exclusive".into())?;
+        if steal {
+            // The lock API does not allow a signal together with `steal`
+            js_sys::Reflect::set(&options, &"steal".into(), &true.into())?;
+        } else {
+            let controller = AbortController::new()?;
+            js_sys::Reflect::set(&options, &"signal".into(), &controller.signal())?;
+            *inner.waiting.borrow_mut() = Some(controller);
+        }
+
+        let this = self.clone();
+        let callback = Closure::once(move |_lock: JsValue| -> js_sys::Promise {
+            let inner = &this.inner;
+            if inner.generation.get() != generation {
+                return js_sys::Promise::resolve(&JsValue::UNDEFINED);
+            }
+            inner.waiting.borrow_mut().take();
+
+            // The lock stays ours until this promise settles
+            let held = js_sys::Promise::new(&mut |resolve, _| {
+                *inner.release.borrow_mut() = Some(resolve);
+            });
+            (inner.on_acquired)();
+            held
+        });
+
+        // Rejects when our request is aborted or another tab steals the lock
+        let this = self.clone();
+        let on_error = Closure::once(move |error: JsValue| {
+            let inner = &this.inner;
+            if inner.generation.get() != generation {
+                return;
+            }
+            web_sys::console::log_2(&"Lost the leader lock:".into(), &error);
+            inner.release.borrow_mut().take();
+            if let Err(e) = this.acquire(false) {
+                web_sys::console::error_2(&"Could not queue for the leader lock:".into(), &e);
+            }
+        });
+
+        let promise = inner.locks.request_with_options(
+            &inner.name,
+            &options,
+            callback.as_ref().unchecked_ref(),
+        );
+        let _ = promise.catch(&on_error);
+        callback.forget();
+        on_error.forget();
+        Ok(())
+    }
+}
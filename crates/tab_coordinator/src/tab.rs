@@ -0,0 +1,411 @@
+use crate::leader::{LeaderChange, LeaderChanges, LeaderListeners};
+use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use sqlite_wrapper::{ffi, Error};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::rc::Rc;
+use tab_coordinator_protocol::console::{error, log, warn};
+use tab_coordinator_protocol::{Capabilities, Election, TabMessage, Transport, PROTOCOL_VERSION};
+
+/// Requests sent through the coordinator that are still waiting for an
+/// answer, keyed by the `request_id` their answer will carry.
+struct Pending<T> {
+    next_id: Cell<u32>,
+    senders: RefCell<HashMap<u32, oneshot::Sender<T>>>,
+}
+
+impl<T> Pending<T> {
+    fn new() -> Pending<T> {
+        Pending {
+            next_id: Cell::new(1),
+            senders: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Allocates a request id and the receiver its answer will arrive on.
+    fn add(&self) -> (u32, oneshot::Receiver<T>) {
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+        let (sender, receiver) = oneshot::channel();
+        self.senders.borrow_mut().insert(id, sender);
+        (id, receiver)
+    }
+
+    fn remove(&self, id: u32) {
+        self.senders.borrow_mut().remove(&id);
+    }
+
+    /// Fails every request still waiting.
+    fn clear(&self) {
+        self.senders.borrow_mut().clear();
+    }
+
+    /// Hands `value` to the request waiting on `id`, if any.
+    fn resolve(&self, id: u32, value: T) {
+        let sender = self.senders.borrow_mut().remove(&id);
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(value);
+            }
+            None => warn(&format!("No pending request {}", id)),
+        }
+    }
+}
+
+/// What runs the requests routed to a tab while it leads. In the browser
+/// this is the tab's SQLite worker.
+pub trait Executor<P> {
+    /// Runs a request from any tab, this one included, and produces its
+    /// reply.
+    fn execute(&self, request: P) -> LocalBoxFuture<'static, P>;
+
+    /// The reply to a request that arrived after this tab stopped leading.
+    fn busy(&self) -> P;
+
+    /// Lets go of the database once another tab has taken over.
+    fn release(&self);
+
+    /// Rolls back anything `tab_id` left open when it went away.
+    fn abandon(&self, tab_id: &str);
+}
+
+/// One tab's side of the coordinator protocol: it answers the coordinator,
+/// runs routed requests while it leads and matches replies to the requests
+/// waiting for them.
+///
+/// It neither knows how messages travel nor what runs the requests, so the
+/// same logic drives [`TabManager`](crate::TabManager) in the browser and
+/// tabs wired together in memory natively. Messages from the coordinator go
+/// to [`handle`](Tab::handle). Clones share the same state.
+pub struct Tab<P, T, E> {
+    inner: Rc<Inner<P, T, E>>,
+}
+
+struct Inner<P, T, E> {
+    tab_id: String,
+    transport: T,
+    executor: E,
+    leader_data: RefCell<String>,
+    /// Leader checks and leader data queries.
+    leader_requests: Pending<String>,
+    /// Routed requests, answered with the leader's reply.
+    query_requests: Pending<P>,
+    leader_listeners: LeaderListeners,
+    is_leader: Cell<bool>,
+    /// Why the coordinator turned this tab away, if it did.
+    rejection: RefCell<Option<String>>,
+}
+
+impl<P, T, E> Clone for Tab<P, T, E> {
+    fn clone(&self) -> Self {
+        Tab {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<P, T, E> Tab<P, T, E>
+where
+    P: Clone + Debug + 'static,
+    T: Transport<P> + 'static,
+    E: Executor<P> + 'static,
+{
+    /// A tab that talks to its coordinator over `transport`. It is not
+    /// known to the coordinator until it [`register`](Tab::register)s.
+    pub fn new(tab_id: String, transport: T, executor: E) -> Tab<P, T, E> {
+        Tab {
+            inner: Rc::new(Inner {
+                tab_id,
+                transport,
+                executor,
+                leader_data: RefCell::new(String::new()),
+                leader_requests: Pending::new(),
+                query_requests: Pending::new(),
+                leader_listeners: LeaderListeners::default(),
+                is_leader: Cell::new(false),
+                rejection: RefCell::new(None),
+            }),
+        }
+    }
+
+    pub fn tab_id(&self) -> &str {
+        &self.inner.tab_id
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.inner.transport
+    }
+
+    /// Whether the coordinator last said this tab leads.
+    pub fn is_leader(&self) -> bool {
+        self.inner.is_leader.get()
+    }
+
+    /// Why the coordinator turned this tab away, if it did.
+    pub fn rejection(&self) -> Option<String> {
+        self.inner.rejection.borrow().clone()
+    }
+
+    pub(crate) fn leader_listeners(&self) -> &LeaderListeners {
+        &self.inner.leader_listeners
+    }
+
+    /// A stream of leadership changes from now on.
+    pub fn leader_changes(&self) -> LeaderChanges {
+        self.inner.leader_listeners.subscribe()
+    }
+
+    pub fn data(&self) -> String {
+        self.inner.leader_data.borrow().clone()
+    }
+
+    /// Sets what this tab answers `query_leader` with while it leads.
+    pub fn set_data(&self, data: String) {
+        *self.inner.leader_data.borrow_mut() = data;
+    }
+
+    fn post(&self, msg: &TabMessage<P>) -> Result<(), T::Error> {
+        self.inner.transport.post(msg)
+    }
+
+    /// Introduces this tab to the coordinator, which answers with the
+    /// current leader.
+    pub fn register(&self, timeout_ms: u32, election: Election) -> Result<(), T::Error> {
+        self.post(&TabMessage::Register {
+            tab_id: self.inner.tab_id.clone(),
+            timeout_ms,
+            election,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::required_for(election),
+        })
+    }
+
+    /// Tells the coordinator this tab is still alive. Does nothing once the
+    /// coordinator has rejected the tab.
+    pub fn heartbeat(&self, timeout_ms: u32) -> Result<(), T::Error> {
+        if self.inner.rejection.borrow().is_some() {
+            return Ok(());
+        }
+        self.post(&TabMessage::Heartbeat {
+            tab_id: self.inner.tab_id.clone(),
+            timeout_ms,
+        })
+    }
+
+    pub fn disconnect(&self) -> Result<(), T::Error> {
+        self.post(&TabMessage::Disconnect {
+            tab_id: self.inner.tab_id.clone(),
+        })
+    }
+
+    pub fn request_leadership(&self) -> Result<(), T::Error> {
+        self.post(&TabMessage::RequestLeadership {
+            tab_id: self.inner.tab_id.clone(),
+        })
+    }
+
+    pub fn yield_leadership(&self) -> Result<(), T::Error> {
+        self.post(&TabMessage::YieldLeadership {
+            tab_id: self.inner.tab_id.clone(),
+        })
+    }
+
+    /// Fails with the reason the coordinator gave if it rejected this tab.
+    fn check_accepted(&self) -> Result<(), Error> {
+        match &*self.inner.rejection.borrow() {
+            Some(reason) => Err(Error::new(ffi::SQLITE_ERROR, reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The error for a request whose answer will never come.
+    fn closed(&self) -> Error {
+        self.check_accepted()
+            .err()
+            .unwrap_or_else(|| Error::new(ffi::SQLITE_ERROR, "Channel closed"))
+    }
+
+    /// Asks the coordinator whether this tab leads right now.
+    pub fn check_leader(&self) -> impl Future<Output = Result<bool, Error>> + 'static {
+        let tab = self.clone();
+        async move {
+            tab.check_accepted()?;
+            let (request_id, receiver) = tab.inner.leader_requests.add();
+            let msg = TabMessage::CheckLeader {
+                tab_id: tab.inner.tab_id.clone(),
+                request_id,
+            };
+            if let Err(e) = tab.post(&msg) {
+                tab.inner.leader_requests.remove(request_id);
+                return Err(Error::new(ffi::SQLITE_ERROR, format!("{:?}", e)));
+            }
+
+            let answer = receiver.await.map_err(|_| tab.closed())?;
+            Ok(answer == "true")
+        }
+    }
+
+    /// Asks the leader for the data it [`set`](Tab::set_data).
+    pub fn query_leader(&self) -> impl Future<Output = Result<String, Error>> + 'static {
+        let tab = self.clone();
+        async move {
+            tab.check_accepted()?;
+            let (request_id, receiver) = tab.inner.leader_requests.add();
+            let msg = TabMessage::QueryLeader {
+                from_tab_id: tab.inner.tab_id.clone(),
+                request_id,
+            };
+            if let Err(e) = tab.post(&msg) {
+                tab.inner.leader_requests.remove(request_id);
+                return Err(Error::new(ffi::SQLITE_ERROR, format!("{:?}", e)));
+            }
+
+            receiver.await.map_err(|_| tab.closed())
+        }
+    }
+
+    /// Sends a request to the leader through the coordinator and waits for
+    /// its reply.
+    pub fn route(&self, request: P) -> impl Future<Output = Result<P, Error>> + 'static {
+        let tab = self.clone();
+        async move {
+            tab.check_accepted()?;
+            let (request_id, receiver) = tab.inner.query_requests.add();
+            let msg = TabMessage::ExecuteQuery {
+                request,
+                from_tab_id: tab.inner.tab_id.clone(),
+                request_id,
+            };
+            if let Err(e) = tab.post(&msg) {
+                tab.inner.query_requests.remove(request_id);
+                return Err(Error::new(ffi::SQLITE_ERROR, format!("{:?}", e)));
+            }
+
+            receiver.await.map_err(|_| tab.closed())
+        }
+    }
+
+    pub fn send_leader_response(
+        &self,
+        from_tab_id: String,
+        request_id: u32,
+    ) -> Result<(), T::Error> {
+        let msg = TabMessage::LeaderDataResponse {
+            data: self.data(),
+            from_tab_id,
+            request_id,
+        };
+        self.post(&msg)
+    }
+
+    /// Handles a message from the coordinator. Running a routed request
+    /// takes a while, so it comes back as a task for the caller to spawn.
+    pub fn handle(&self, msg: TabMessage<P>) -> Option<LocalBoxFuture<'static, ()>> {
+        let inner = &self.inner;
+        log(&format!("Tab message: {:?}", msg));
+
+        match msg {
+            TabMessage::LeaderResponse {
+                is_leader,
+                request_id,
+            } => {
+                inner
+                    .leader_requests
+                    .resolve(request_id, is_leader.to_string());
+            }
+            TabMessage::LeaderDataResponse {
+                data, request_id, ..
+            } => {
+                inner.leader_requests.resolve(request_id, data);
+            }
+            TabMessage::QueryLeader {
+                from_tab_id,
+                request_id,
+            } => {
+                if let Err(e) = self.send_leader_response(from_tab_id, request_id) {
+                    error(&format!("Could not send leader data: {:?}", e));
+                }
+            }
+            TabMessage::ExecuteQuery {
+                request,
+                from_tab_id,
+                request_id,
+            } => {
+                let tab = self.clone();
+                return Some(Box::pin(async move {
+                    // Leadership may have moved since the coordinator forwarded this
+                    let is_leader = tab.check_leader().await.unwrap_or(false);
+
+                    // Both the requester and, when it is us, our own caller get the
+                    // reply as is
+                    let reply = if is_leader {
+                        tab.inner.executor.execute(request).await
+                    } else {
+                        tab.inner.executor.busy()
+                    };
+
+                    // Back to the original requester, which may be us, through the
+                    // coordinator
+                    let response = TabMessage::QueryResponse {
+                        reply,
+                        from_tab_id: from_tab_id.clone(),
+                        request_id,
+                    };
+                    match tab.post(&response) {
+                        Ok(()) => log(&format!("Sent query response to tab: {}", from_tab_id)),
+                        Err(e) => error(&format!("Could not send query response: {:?}", e)),
+                    }
+                }));
+            }
+            // Only process if we're the original requester
+            TabMessage::QueryResponse {
+                reply,
+                from_tab_id,
+                request_id,
+            } if from_tab_id == inner.tab_id => {
+                inner.query_requests.resolve(request_id, reply);
+            }
+            TabMessage::LeaderChanged { leader_id } => {
+                log(&format!("Leader is now {:?}", leader_id));
+                let is_leader = leader_id.as_ref() == Some(&inner.tab_id);
+                let was_leader = inner.is_leader.replace(is_leader);
+
+                // Hand the database over: our worker holds it until it lets go,
+                // and the new leader's worker waits for that
+                if was_leader && !is_leader {
+                    inner.executor.release();
+                }
+
+                inner.leader_listeners.notify(LeaderChange {
+                    is_leader,
+                    leader_id,
+                });
+            }
+            TabMessage::Rejected {
+                tab_id,
+                reason,
+                protocol_version,
+            } => {
+                if tab_id != inner.tab_id {
+                    return None;
+                }
+                error(&format!(
+                    "Coordinator (protocol version {}) rejected this tab: {}",
+                    protocol_version, reason
+                ));
+                *inner.rejection.borrow_mut() = Some(reason);
+                inner.leader_requests.clear();
+                inner.query_requests.clear();
+            }
+            TabMessage::TabLeft { tab_id } => {
+                // Roll back anything the departed tab left open
+                inner.executor.abandon(&tab_id);
+            }
+            _ => {}
+        }
+        None
+    }
+}
@@ -0,0 +1,86 @@
+use crate::broadcast::BroadcastCoordinator;
+use crate::{TabManagerOptions, TabMessage};
+use std::rc::Rc;
+use tab_coordinator_protocol::{JsPayload, Transport};
+use wasm_bindgen::prelude::*;
+use web_sys::{MessagePort, SharedWorker};
+
+/// Handles every [`TabMessage`] addressed to this tab.
+pub type Handler<P = JsPayload> = Rc<dyn Fn(TabMessage<P>)>;
+
+/// How a tab reaches the coordinator.
+#[derive(Clone)]
+pub(crate) enum Connection {
+    /// A port of the coordinating shared worker.
+    SharedWorker(MessagePort),
+    /// Tabs coordinating among themselves over a `BroadcastChannel`, for
+    /// browsers without `SharedWorker`.
+    Broadcast(Rc<BroadcastCoordinator>),
+}
+
+impl Connection {
+    /// Connects to the shared worker, falling back to a `BroadcastChannel`
+    /// when `SharedWorker` is missing or refuses to start.
+    pub(crate) fn connect(
+        options: &TabManagerOptions,
+        tab_id: &str,
+    ) -> Result<Connection, JsValue> {
+        let shared_worker = match &options.name {
+            Some(name) => SharedWorker::new_with_str(&options.shared_worker_url, name),
+            None => SharedWorker::new(&options.shared_worker_url),
+        };
+        match shared_worker {
+            Ok(shared_worker) => Ok(Connection::SharedWorker(shared_worker.port())),
+            Err(e) => {
+                web_sys::console::warn_2(
+                    &"SharedWorker unavailable, coordinating over BroadcastChannel:".into(),
+                    &e,
+                );
+                let name = format!(
+                    "tab_coordinator:{}",
+                    options.name.as_deref().unwrap_or_default()
+                );
+                let coordinator = BroadcastCoordinator::open(&name, tab_id)?;
+                Ok(Connection::Broadcast(coordinator))
+            }
+        }
+    }
+
+    /// Starts passing incoming messages to `handler`.
+    pub(crate) fn set_handler(&self, handler: Handler) {
+        match self {
+            Connection::SharedWorker(port) => {
+                let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
+                    if let Ok(msg) = serde_wasm_bindgen::from_value::<TabMessage>(e.data()) {
+                        handler(msg);
+                    }
+                })
+                    as Box<dyn FnMut(web_sys::MessageEvent)>);
+                port.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+                onmessage.forget();
+            }
+            Connection::Broadcast(coordinator) => coordinator.set_handler(handler),
+        }
+    }
+
+    /// The shared worker port, if that is what we use.
+    pub(crate) fn port(&self) -> Option<MessagePort> {
+        match self {
+            Connection::SharedWorker(port) => Some(port.clone()),
+            Connection::Broadcast(_) => None,
+        }
+    }
+}
+
+impl Transport<JsPayload> for Connection {
+    type Error = JsValue;
+
+    fn post(&self, msg: &TabMessage) -> Result<(), JsValue> {
+        match self {
+            Connection::SharedWorker(port) => port.post(msg),
+            Connection::Broadcast(coordinator) => {
+                coordinator.post(msg.clone(), js_sys::Date::now())
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! Tabs and the shared worker's coordinator wired together in memory.
+
+use futures::channel::oneshot;
+use futures::executor::LocalPool;
+use futures::future::LocalBoxFuture;
+use futures::task::LocalSpawnExt;
+use sqlite_wrapper::{
+    Error, Params, Scheduler, WorkerMessage, WorkerRequest, SESSION_IDLE_TIMEOUT_MS,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use tab_coordinator::{Election, Executor, Tab, TabMessage};
+use tab_coordinator_protocol::memory::Mailbox;
+use tab_coordinator_protocol::{Capabilities, PROTOCOL_VERSION};
+use tab_coordinator_shared_worker::TabState;
+
+const TIMEOUT_MS: u32 = 5000;
+
+/// Requests a [`FakeWorker`] has yet to answer, by message id.
+type Waiting = Rc<RefCell<HashMap<u32, (String, oneshot::Sender<String>)>>>;
+
+/// Stands in for a tab's SQLite worker: it answers with who ran what. Like
+/// the real one it holds back other requests while a transaction is open,
+/// e.g. after `begin t1` until `commit t1`, running only `t1: ...`.
+#[derive(Clone)]
+struct FakeWorker {
+    tab_id: String,
+    releases: Rc<Cell<u32>>,
+    abandoned: Rc<RefCell<Vec<String>>>,
+    scheduler: Rc<RefCell<Scheduler>>,
+    waiting: Waiting,
+    now: Rc<Cell<f64>>,
+}
+
+impl FakeWorker {
+    fn new(tab_id: &str) -> FakeWorker {
+        FakeWorker {
+            tab_id: tab_id.to_string(),
+            releases: Rc::new(Cell::new(0)),
+            abandoned: Rc::new(RefCell::new(Vec::new())),
+            scheduler: Rc::new(RefCell::new(Scheduler::new())),
+            waiting: Rc::new(RefCell::new(HashMap::new())),
+            now: Rc::new(Cell::new(0.0)),
+        }
+    }
+
+    /// Runs every request that may run now.
+    fn run(&self) {
+        loop {
+            let next = self.scheduler.borrow_mut().next(self.now.get());
+            let Some(message) = next else {
+                return;
+            };
+            match message.request {
+                WorkerRequest::Begin { txn, owner } => {
+                    self.scheduler
+                        .borrow_mut()
+                        .begin(txn, owner, self.now.get());
+                }
+                WorkerRequest::Commit { .. } => self.scheduler.borrow_mut().end(),
+                _ => {}
+            }
+            if let Some((request, reply)) = self.waiting.borrow_mut().remove(&message.id) {
+                let _ = reply.send(format!("{} ran {}", self.tab_id, request));
+            }
+        }
+    }
+
+    /// Lets time pass, rolling back a transaction left idle too long.
+    fn advance(&self, now: f64) {
+        self.now.set(now);
+        if self.scheduler.borrow_mut().expire(now).is_some() {
+            self.run();
+        }
+    }
+}
+
+fn worker_request(request: &str) -> WorkerRequest {
+    match request.split_once(' ') {
+        Some(("begin", txn)) => WorkerRequest::Begin {
+            txn: txn.to_string(),
+            owner: String::new(),
+        },
+        Some(("commit", txn)) => WorkerRequest::Commit {
+            txn: txn.to_string(),
+        },
+        _ => {
+            let (txn, sql) = match request.split_once(": ") {
+                Some((txn, sql)) => (Some(txn.to_string()), sql),
+                None => (None, request),
+            };
+            WorkerRequest::Execute {
+                sql: sql.to_string(),
+                params: Params::None,
+                txn,
+            }
+        }
+    }
+}
+
+impl Executor<String> for FakeWorker {
+    fn execute(&self, request: String) -> LocalBoxFuture<'static, String> {
+        let message = WorkerMessage::new(worker_request(&request));
+        let (reply, receiver) = oneshot::channel();
+        self.waiting
+            .borrow_mut()
+            .insert(message.id, (request, reply));
+        self.scheduler.borrow_mut().push(message);
+        self.run();
+        Box::pin(async move { receiver.await.unwrap_or_else(|_| "dropped".to_string()) })
+    }
+
+    fn busy(&self) -> String {
+        "busy".to_string()
+    }
+
+    fn release(&self) {
+        self.releases.set(self.releases.get() + 1);
+    }
+
+    fn abandon(&self, tab_id: &str) {
+        self.abandoned.borrow_mut().push(tab_id.to_string());
+    }
+}
+
+struct TestTab {
+    tab: Tab<String, Rc<Mailbox<String>>, FakeWorker>,
+    worker: FakeWorker,
+    /// What the tab posts to the coordinator.
+    outbox: Rc<Mailbox<String>>,
+    /// The coordinator's port to the tab.
+    inbox: Rc<Mailbox<String>>,
+}
+
+type Reply = Rc<RefCell<Option<Result<String, Error>>>>;
+
+struct Browser {
+    coordinator: TabState<String, Mailbox<String>>,
+    tabs: Vec<TestTab>,
+    pool: LocalPool,
+    now: f64,
+}
+
+impl Browser {
+    fn new() -> Browser {
+        Browser {
+            coordinator: TabState::new(),
+            tabs: Vec::new(),
+            pool: LocalPool::new(),
+            now: 0.0,
+        }
+    }
+
+    /// Opens a tab and lets it register.
+    fn open(&mut self, tab_id: &str) -> usize {
+        self.open_with(tab_id, Election::Coordinator)
+    }
+
+    fn open_with(&mut self, tab_id: &str, election: Election) -> usize {
+        let worker = FakeWorker::new(tab_id);
+        let outbox = Rc::new(Mailbox::new());
+        let tab = Tab::new(tab_id.to_string(), outbox.clone(), worker.clone());
+        tab.register(TIMEOUT_MS, election).unwrap();
+        self.tabs.push(TestTab {
+            tab,
+            worker,
+            outbox,
+            inbox: Rc::new(Mailbox::new()),
+        });
+        self.settle();
+        self.tabs.len() - 1
+    }
+
+    fn tab(&self, index: usize) -> &Tab<String, Rc<Mailbox<String>>, FakeWorker> {
+        &self.tabs[index].tab
+    }
+
+    fn leader(&self) -> Option<String> {
+        self.coordinator.get_leader().cloned()
+    }
+
+    /// Hands everything the tabs posted to the coordinator. Returns whether
+    /// there was anything.
+    fn pump_outboxes(&mut self) -> bool {
+        let mut moved = false;
+        for tab in &self.tabs {
+            for msg in tab.outbox.drain() {
+                self.coordinator.handle(msg, &tab.inbox, self.now);
+                moved = true;
+            }
+        }
+        moved
+    }
+
+    /// Hands everything the coordinator posted to the tabs and runs what
+    /// they start. Returns whether there was anything.
+    fn pump_inboxes(&mut self) -> bool {
+        let mut moved = false;
+        for tab in &self.tabs {
+            for msg in tab.inbox.drain() {
+                if let Some(task) = tab.tab.handle(msg) {
+                    self.pool.spawner().spawn_local(task).unwrap();
+                }
+                moved = true;
+            }
+        }
+        self.pool.run_until_stalled();
+        moved
+    }
+
+    /// Delivers messages until nobody has anything left to say.
+    fn settle(&mut self) {
+        while self.pump_outboxes() | self.pump_inboxes() {}
+    }
+
+    /// Starts routing `request` from a tab. The reply shows up once the
+    /// messages have been delivered.
+    fn route(&mut self, index: usize, request: &str) -> Reply {
+        let reply: Reply = Rc::new(RefCell::new(None));
+        let reply_clone = reply.clone();
+        let routed = self.tab(index).route(request.to_string());
+        self.pool
+            .spawner()
+            .spawn_local(async move {
+                *reply_clone.borrow_mut() = Some(routed.await);
+            })
+            .unwrap();
+        self.pool.run_until_stalled();
+        reply
+    }
+
+    /// Closes a tab the way a page unload does.
+    fn close(&mut self, index: usize) {
+        self.tab(index).disconnect().unwrap();
+        self.pump_outboxes();
+        self.crash(index);
+        self.settle();
+    }
+
+    /// The tab vanishes without a word, e.g. its process was killed.
+    fn crash(&mut self, index: usize) {
+        self.tabs[index].outbox.close();
+        self.tabs[index].inbox.close();
+    }
+
+    /// Lets time pass, with every live tab sending heartbeats.
+    fn advance(&mut self, ms: f64) {
+        self.now += ms;
+        for tab in &self.tabs {
+            tab.worker.advance(self.now);
+            let _ = tab.tab.heartbeat(TIMEOUT_MS);
+        }
+        self.pool.run_until_stalled();
+        self.pump_outboxes();
+        self.coordinator.sweep(self.now);
+        self.settle();
+    }
+}
+
+fn reply(reply: &Reply) -> String {
+    reply
+        .borrow_mut()
+        .take()
+        .expect("no reply yet")
+        .expect("request failed")
+}
+
+#[test]
+fn first_tab_leads_and_later_tabs_follow() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+    let c = browser.open("c");
+
+    assert_eq!(browser.leader().as_deref(), Some("a"));
+    assert!(browser.tab(a).is_leader());
+    assert!(!browser.tab(b).is_leader());
+    assert!(!browser.tab(c).is_leader());
+}
+
+#[test]
+fn next_tab_leads_when_the_leader_closes() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+    browser.open("c");
+
+    browser.close(a);
+
+    assert_eq!(browser.leader().as_deref(), Some("b"));
+    assert!(browser.tab(b).is_leader());
+    assert_eq!(browser.coordinator.tabs().count(), 2);
+}
+
+#[test]
+fn leader_rolls_back_for_a_tab_that_closes() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+
+    browser.close(b);
+
+    assert_eq!(*browser.tabs[a].worker.abandoned.borrow(), vec!["b"]);
+}
+
+#[test]
+fn abandoned_transaction_does_not_block_other_tabs_for_long() {
+    let mut browser = Browser::new();
+    browser.open("a");
+    let b = browser.open("b");
+    let c = browser.open("c");
+
+    // b begins a transaction and, still open, never finishes it
+    let begun = browser.route(b, "begin t1");
+    browser.settle();
+    assert_eq!(reply(&begun), "a ran begin t1");
+
+    let routed = browser.route(c, "select");
+    browser.settle();
+    browser.advance(SESSION_IDLE_TIMEOUT_MS / 2.0);
+    assert!(routed.borrow().is_none());
+
+    browser.advance(SESSION_IDLE_TIMEOUT_MS / 2.0);
+    assert_eq!(reply(&routed), "a ran select");
+}
+
+#[test]
+fn silent_leader_is_replaced_after_its_timeout() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+
+    browser.crash(a);
+    browser.advance(TIMEOUT_MS as f64 / 2.0);
+    assert_eq!(browser.leader().as_deref(), Some("a"));
+
+    browser.advance(TIMEOUT_MS as f64);
+    assert_eq!(browser.leader().as_deref(), Some("b"));
+    assert!(browser.tab(b).is_leader());
+}
+
+#[test]
+fn concurrent_queries_each_get_their_own_reply() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+    let c = browser.open("c");
+
+    let mut replies = Vec::new();
+    for tab in [a, b, c] {
+        for i in 0..3 {
+            let request = format!("query {} from tab {}", i, tab);
+            let routed = browser.route(tab, &request);
+            replies.push((request, routed));
+        }
+    }
+    browser.settle();
+
+    for (request, routed) in &replies {
+        assert_eq!(reply(routed), format!("a ran {}", request));
+    }
+}
+
+#[test]
+fn unanswered_queries_are_replayed_on_the_next_leader() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+
+    // The coordinator forwards the query, but the leader dies before running it
+    let routed = browser.route(b, "insert");
+    browser.pump_outboxes();
+    browser.crash(a);
+    browser.advance(TIMEOUT_MS as f64 + 1.0);
+
+    assert_eq!(reply(&routed), "b ran insert");
+}
+
+#[test]
+fn old_leader_releases_the_database_on_handoff() {
+    let mut browser = Browser::new();
+    let a = browser.open("a");
+    let b = browser.open("b");
+
+    browser.tab(b).request_leadership().unwrap();
+    browser.settle();
+
+    assert_eq!(browser.leader().as_deref(), Some("b"));
+    assert_eq!(browser.tabs[a].worker.releases.get(), 1);
+    assert_eq!(browser.tabs[b].worker.releases.get(), 0);
+
+    let routed = browser.route(a, "select");
+    browser.settle();
+    assert_eq!(reply(&routed), "b ran select");
+}
+
+#[test]
+fn busy_reply_of_a_lock_holder_that_yields_is_not_the_answer() {
+    let mut browser = Browser::new();
+    let a = browser.open_with("a", Election::Lock);
+    browser.tab(a).request_leadership().unwrap();
+    let b = browser.open_with("b", Election::Lock);
+    browser.settle();
+    assert_eq!(browser.leader().as_deref(), Some("a"));
+
+    // The query reaches a, which lets go of the lock before running it
+    let routed = browser.route(b, "insert");
+    browser.pump_outboxes();
+    browser.tab(a).yield_leadership().unwrap();
+    browser.settle();
+    assert_eq!(browser.leader(), None);
+    assert!(routed.borrow().is_none());
+
+    // b gets the lock next
+    browser.tab(b).request_leadership().unwrap();
+    browser.settle();
+    assert_eq!(reply(&routed), "b ran insert");
+}
+
+#[test]
+fn tab_of_another_protocol_version_is_rejected() {
+    let mut browser = Browser::new();
+    browser.open("a");
+
+    // A tab from a cached bundle registers with an older protocol
+    let inbox = Rc::new(Mailbox::new());
+    let register = TabMessage::Register {
+        tab_id: "stale".to_string(),
+        timeout_ms: TIMEOUT_MS,
+        election: Election::Coordinator,
+        protocol_version: PROTOCOL_VERSION - 1,
+        capabilities: Capabilities::NONE,
+    };
+    browser.coordinator.handle(register, &inbox, browser.now);
+
+    let rejected = inbox.take().unwrap();
+    assert!(matches!(rejected, TabMessage::Rejected { ref tab_id, .. } if tab_id == "stale"));
+    assert_eq!(browser.coordinator.tabs().count(), 1);
+
+    // The tab gives up instead of waiting for answers that never come
+    let worker = FakeWorker::new("stale");
+    let stale = Tab::new("stale".to_string(), Rc::new(Mailbox::new()), worker);
+    stale.handle(rejected);
+    assert!(stale.rejection().is_some());
+    assert!(futures::executor::block_on(stale.route("select".to_string())).is_err());
+}
+
+#[test]
+fn tab_with_another_election_is_rejected() {
+    let mut browser = Browser::new();
+    let a = browser.open_with("a", Election::Lock);
+    browser.tab(a).request_leadership().unwrap();
+    browser.settle();
+
+    let b = browser.open_with("b", Election::Coordinator);
+
+    assert!(browser.tab(b).rejection().is_some());
+    assert_eq!(browser.coordinator.tabs().collect::<Vec<_>>(), ["a"]);
+    assert_eq!(browser.leader().as_deref(), Some("a"));
+}
+
+#[test]
+fn tab_from_before_versioning_is_rejected() {
+    let mut browser = Browser::new();
+    browser.open("a");
+
+    // What the baseline bundle posts: no timeout, election or version
+    let register: TabMessage<String> =
+        serde_json::from_str(r#"{"type":"Register","tab_id":"stale"}"#).unwrap();
+    let inbox = Rc::new(Mailbox::new());
+    browser.coordinator.handle(register, &inbox, browser.now);
+
+    match inbox.take() {
+        Some(TabMessage::Rejected {
+            tab_id,
+            protocol_version,
+            ..
+        }) => {
+            assert_eq!(tab_id, "stale");
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+        }
+        other => panic!("expected Rejected, got {:?}", other),
+    }
+    assert_eq!(browser.coordinator.tabs().count(), 1);
+}
@@ -0,0 +1,245 @@
+//! The messages tabs and their coordinator exchange, whether the coordinator
+//! is the shared worker or the tabs themselves over a `BroadcastChannel`.
+//!
+//! A tab and a coordinator built from different versions of this crate, e.g.
+//! a tab still running a cached bundle, find out when the tab registers: the
+//! coordinator answers a [`TabMessage::Register`] it cannot serve with
+//! [`TabMessage::Rejected`] instead of misreading what follows.
+//!
+//! Messages travel over a [`Transport`]. In the browser that is a
+//! `MessagePort`; [`memory::Mailbox`] keeps them in process, so tabs and a
+//! coordinator can run together natively, e.g. in tests.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::BitOr;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+pub mod console;
+pub mod memory;
+
+/// Version of the messages below. Bump it whenever a message changes shape
+/// or meaning; tabs and coordinators only talk to the same version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A message between a tab and its coordinator. `P` is what routed requests
+/// and their replies are carried as, a [`JsPayload`] in the browser.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(bound(serialize = "P: Serialize", deserialize = "P: Deserialize<'de>"))]
+pub enum TabMessage<P = JsPayload> {
+    /// The first message a tab sends. Tabs from before versioning send only
+    /// `tab_id`, so every other field has a default and such a tab reads as
+    /// protocol version 0, to be rejected rather than go unanswered.
+    Register {
+        tab_id: String,
+        /// How long the coordinator waits for a heartbeat before dropping us.
+        #[serde(default)]
+        timeout_ms: u32,
+        #[serde(default)]
+        election: Election,
+        #[serde(default)]
+        protocol_version: u32,
+        /// What the tab needs from its coordinator.
+        #[serde(default)]
+        capabilities: Capabilities,
+    },
+    /// Sent to a tab whose `Register` the coordinator cannot accept. The
+    /// coordinator ignores the tab from then on.
+    Rejected {
+        tab_id: String,
+        reason: String,
+        /// The version the coordinator speaks.
+        protocol_version: u32,
+    },
+    /// Sent every heartbeat interval to show the tab is still alive.
+    Heartbeat {
+        tab_id: String,
+        timeout_ms: u32,
+    },
+    /// Broadcast by the coordinator when a new tab has taken over.
+    LeaderChanged {
+        leader_id: Option<String>,
+    },
+    /// Asks the coordinator to make `tab_id` the leader.
+    RequestLeadership {
+        tab_id: String,
+    },
+    /// Asks the coordinator to let the next tab lead instead of `tab_id`.
+    YieldLeadership {
+        tab_id: String,
+    },
+    // Requests carry a `request_id` chosen by the requesting tab, which its
+    // answer echoes back
+    CheckLeader {
+        tab_id: String,
+        request_id: u32,
+    },
+    LeaderResponse {
+        is_leader: bool,
+        request_id: u32,
+    },
+    QueryLeader {
+        from_tab_id: String,
+        request_id: u32,
+    },
+    LeaderDataResponse {
+        data: String,
+        from_tab_id: String,
+        request_id: u32,
+    },
+    /// A request for the leader's SQLite worker, routed from `from_tab_id`.
+    /// The coordinator only forwards it.
+    ExecuteQuery {
+        request: P,
+        from_tab_id: String,
+        request_id: u32,
+    },
+    /// The leader worker's reply to an `ExecuteQuery`, passed on as is, so
+    /// the requester reads exactly what the leader's own request would have.
+    QueryResponse {
+        reply: P,
+        from_tab_id: String,
+        request_id: u32,
+    },
+    Disconnect {
+        tab_id: String,
+    },
+    /// Sent to the leader when another tab has gone away.
+    TabLeft {
+        tab_id: String,
+    },
+}
+
+/// A JS value carried through the coordinator untouched, e.g. a
+/// `WorkerRequest` or `WorkerReply` in its JS form. Its `Debug` output
+/// leaves the value out, since it can hold query parameters and results.
+#[derive(Clone)]
+pub struct JsPayload(pub JsValue);
+
+impl fmt::Debug for JsPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JsPayload(..)")
+    }
+}
+
+impl Serialize for JsPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde_wasm_bindgen::preserve::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for JsPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JsPayload, D::Error> {
+        serde_wasm_bindgen::preserve::deserialize(deserializer).map(JsPayload)
+    }
+}
+
+/// Where [`TabMessage`]s are posted: a tab's link to its coordinator, or the
+/// coordinator's link to one tab.
+pub trait Transport<P> {
+    type Error: fmt::Debug;
+
+    fn post(&self, msg: &TabMessage<P>) -> Result<(), Self::Error>;
+}
+
+impl<P, T: Transport<P> + ?Sized> Transport<P> for Rc<T> {
+    type Error = T::Error;
+
+    fn post(&self, msg: &TabMessage<P>) -> Result<(), T::Error> {
+        (**self).post(msg)
+    }
+}
+
+impl Transport<JsPayload> for web_sys::MessagePort {
+    type Error = JsValue;
+
+    fn post(&self, msg: &TabMessage) -> Result<(), JsValue> {
+        self.post_message(&serde_wasm_bindgen::to_value(msg)?)
+    }
+}
+
+/// Reaches every other tab on the channel.
+impl Transport<JsPayload> for web_sys::BroadcastChannel {
+    type Error = JsValue;
+
+    fn post(&self, msg: &TabMessage) -> Result<(), JsValue> {
+        self.post_message(&serde_wasm_bindgen::to_value(msg)?)
+    }
+}
+
+/// How the leader is chosen. Every tab of a database must use the same one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Election {
+    /// The coordinator picks the longest-registered tab.
+    #[default]
+    Coordinator,
+    /// The tab holding an exclusive Web Lock leads, and the browser hands the
+    /// lock to the next tab when the holder dies. The coordinator still
+    /// routes requests, to whichever tab holds the lock. The holder claims
+    /// leadership with `RequestLeadership` when it gets the lock.
+    Lock,
+}
+
+/// Features of the coordinator a tab relies on, as bit flags.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Follows the lock holder under [`Election::Lock`].
+    pub const LOCK_ELECTION: Capabilities = Capabilities(1);
+    /// Sends unanswered `ExecuteQuery`s again when the leader changes, and
+    /// drops answers from former leaders.
+    pub const REPLAY: Capabilities = Capabilities(1 << 1);
+    /// Everything this version of the protocol offers.
+    pub const SUPPORTED: Capabilities = Capabilities(Self::LOCK_ELECTION.0 | Self::REPLAY.0);
+
+    /// What a tab using `election` needs.
+    pub fn required_for(election: Election) -> Capabilities {
+        match election {
+            Election::Coordinator => Capabilities::REPLAY,
+            Election::Lock => Capabilities::REPLAY | Capabilities::LOCK_ELECTION,
+        }
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// Checks whether a coordinator of this version can serve a tab that
+/// registered with `protocol_version` and `capabilities`, and if not, says
+/// why.
+pub fn check_register(protocol_version: u32, capabilities: Capabilities) -> Result<(), String> {
+    if protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "Tab speaks coordinator protocol version {} but the coordinator speaks version {}; \
+             reload every tab of the app",
+            protocol_version, PROTOCOL_VERSION
+        ));
+    }
+    if !Capabilities::SUPPORTED.contains(capabilities) {
+        return Err(format!(
+            "Tab needs coordinator capabilities {:#b} but the coordinator offers {:#b}",
+            capabilities.bits(),
+            Capabilities::SUPPORTED.bits()
+        ));
+    }
+    Ok(())
+}
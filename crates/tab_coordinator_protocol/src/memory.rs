@@ -0,0 +1,75 @@
+//! An in-memory [`Transport`], for running tabs and a coordinator in one
+//! process.
+
+use crate::{TabMessage, Transport};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+/// Queues whatever is posted to it until the other side takes it.
+///
+/// Nothing is delivered by itself: whoever owns both sides moves messages
+/// along, in whatever order it likes.
+pub struct Mailbox<P> {
+    messages: RefCell<VecDeque<TabMessage<P>>>,
+    closed: Cell<bool>,
+}
+
+/// Returned when posting to a closed [`Mailbox`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl<P> Mailbox<P> {
+    pub fn new() -> Mailbox<P> {
+        Mailbox {
+            messages: RefCell::new(VecDeque::new()),
+            closed: Cell::new(false),
+        }
+    }
+
+    /// The oldest message not taken yet.
+    pub fn take(&self) -> Option<TabMessage<P>> {
+        self.messages.borrow_mut().pop_front()
+    }
+
+    /// Every message not taken yet, oldest first.
+    pub fn drain(&self) -> Vec<TabMessage<P>> {
+        self.messages.borrow_mut().drain(..).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.borrow().is_empty()
+    }
+
+    /// Drops what is queued and refuses anything posted from now on, like
+    /// the port of a closed tab.
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.messages.borrow_mut().clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+}
+
+impl<P> Default for Mailbox<P> {
+    fn default() -> Mailbox<P> {
+        Mailbox::new()
+    }
+}
+
+impl<P: Clone> Transport<P> for Mailbox<P> {
+    type Error = Closed;
+
+    fn post(&self, msg: &TabMessage<P>) -> Result<(), Closed> {
+        if self.closed.get() {
+            return Err(Closed);
+        }
+        self.messages.borrow_mut().push_back(msg.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::rc::Rc;
+use tab_coordinator_protocol::console::{error, log};
+use tab_coordinator_protocol::{check_register, Election, TabMessage, Transport, PROTOCOL_VERSION};
+
+/// When a tab was last heard from and how long it may stay silent, in ms.
+struct Liveness {
+    last_seen: f64,
+    timeout: f64,
+}
+
+/// A routed worker request that has not been answered yet.
+struct InFlight<P> {
+    from_tab_id: String,
+    request_id: u32,
+    request: P,
+    /// The leader it was last forwarded to, `None` while waiting for one.
+    leader: Option<String>,
+    /// The request id it was last forwarded with. Every forward gets a new
+    /// one, so a leader answering an earlier forward, e.g. with a busy
+    /// reply before leadership came back to it, is not mistaken for the
+    /// answer.
+    forward_id: u32,
+}
+
+/// Everything the coordinator knows about the tabs, and what it does with
+/// their messages.
+///
+/// Each tab is reached through its own `T`, and the coordinator answers
+/// whichever one a message came in on. Times are ms on any clock that only
+/// moves forward, `Date.now()` in the shared worker.
+pub struct TabState<P, T> {
+    ports: HashMap<String, Rc<T>>,
+    tabs: VecDeque<String>,
+    liveness: HashMap<String, Liveness>,
+    in_flight: Vec<InFlight<P>>,
+    /// Set by the first tab to register. Every tab must elect the same way.
+    election: Option<Election>,
+    /// The tab that last claimed the lock, under lock election.
+    lock_holder: Option<String>,
+    /// Tabs turned away at registration, which must not rejoin by heartbeat.
+    rejected: HashSet<String>,
+    next_forward_id: u32,
+}
+
+impl<P: Clone + Debug, T: Transport<P>> TabState<P, T> {
+    pub fn new() -> Self {
+        Self {
+            ports: HashMap::new(),
+            tabs: VecDeque::new(),
+            liveness: HashMap::new(),
+            in_flight: Vec::new(),
+            election: None,
+            lock_holder: None,
+            rejected: HashSet::new(),
+            next_forward_id: 1,
+        }
+    }
+
+    pub fn get_leader(&self) -> Option<&String> {
+        match self.election.unwrap_or_default() {
+            Election::Coordinator => self.tabs.front(),
+            Election::Lock => self
+                .tabs
+                .front()
+                .filter(|id| self.lock_holder.as_ref() == Some(*id)),
+        }
+    }
+
+    /// Registered tabs, in the order they will lead.
+    pub fn tabs(&self) -> impl Iterator<Item = &String> {
+        self.tabs.iter()
+    }
+
+    /// Handles `msg`, which arrived on `port`.
+    pub fn handle(&mut self, msg: TabMessage<P>, port: &Rc<T>, now: f64) {
+        log(&format!("📨 Received message in shared worker: {:?}", msg));
+
+        match msg {
+            TabMessage::Register {
+                tab_id,
+                timeout_ms,
+                election,
+                protocol_version,
+                capabilities,
+            } => {
+                let checked = check_register(protocol_version, capabilities)
+                    .and_then(|()| self.check_election(election));
+                if let Err(reason) = checked {
+                    error(&format!("🚫 Rejecting tab {}: {}", tab_id, reason));
+                    let response = TabMessage::Rejected {
+                        tab_id: tab_id.clone(),
+                        reason,
+                        protocol_version: PROTOCOL_VERSION,
+                    };
+                    self.rejected.insert(tab_id);
+                    send(port, &response);
+                    return;
+                }
+                log(&format!("📝 Registering tab: {}", tab_id));
+                self.election.get_or_insert(election);
+                self.join_tab(tab_id, port.clone(), timeout_ms, now);
+                log(&format!("📊 Current tabs: {:?}", self.tabs));
+            }
+            TabMessage::CheckLeader { tab_id, request_id } => {
+                let is_leader = self.get_leader() == Some(&tab_id);
+                log(&format!("Tab {} is_leader: {}", tab_id, is_leader));
+                let response = TabMessage::LeaderResponse {
+                    is_leader,
+                    request_id,
+                };
+                send(port, &response);
+            }
+            TabMessage::QueryLeader { .. } => {
+                let leader_port = self.get_leader().and_then(|id| self.ports.get(id));
+                match leader_port {
+                    Some(leader_port) => send(leader_port, &msg),
+                    None => error("❌ ERROR: No leader found in tab state!"),
+                }
+            }
+            TabMessage::LeaderDataResponse {
+                ref from_tab_id, ..
+            } => match self.ports.get(from_tab_id) {
+                Some(requester_port) => send(requester_port, &msg),
+                None => error(&format!("❌ ERROR: No port found for tab {}", from_tab_id)),
+            },
+            TabMessage::Heartbeat { tab_id, timeout_ms } => {
+                let known = self.touch(&tab_id, now);
+                if !known && !self.rejected.contains(&tab_id) {
+                    // Dropped while it was frozen; it rejoins behind the others
+                    self.join_tab(tab_id, port.clone(), timeout_ms, now);
+                }
+            }
+            TabMessage::RequestLeadership { tab_id } => {
+                log(&format!("🙋 Tab {} asks to lead", tab_id));
+                self.promote(&tab_id);
+            }
+            TabMessage::YieldLeadership { tab_id } => {
+                log(&format!("🙇 Tab {} steps back", tab_id));
+                self.demote(&tab_id);
+            }
+            TabMessage::Disconnect { tab_id } => self.drop_tab(&tab_id),
+            TabMessage::ExecuteQuery {
+                request,
+                from_tab_id,
+                request_id,
+            } => {
+                // Tracked until answered, so it can be replayed if the leader
+                // goes away first
+                self.in_flight.push(InFlight {
+                    from_tab_id,
+                    request_id,
+                    request,
+                    leader: None,
+                    forward_id: 0,
+                });
+                self.dispatch();
+            }
+            TabMessage::QueryResponse {
+                reply,
+                from_tab_id,
+                request_id,
+            } => {
+                let Some(request_id) = self.complete(&from_tab_id, request_id, port) else {
+                    log("Ignoring stale query response");
+                    return;
+                };
+                if let Some(requester_port) = self.ports.get(&from_tab_id) {
+                    let response = TabMessage::QueryResponse {
+                        reply,
+                        from_tab_id,
+                        request_id,
+                    };
+                    send(requester_port, &response);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops every tab that has stopped sending heartbeats, e.g. because it
+    /// crashed or was frozen, promoting the next tab if the leader was one.
+    pub fn sweep(&mut self, now: f64) {
+        for tab_id in self.expired(now) {
+            log(&format!("💀 Tab {} stopped responding", tab_id));
+            self.drop_tab(&tab_id);
+        }
+    }
+
+    fn register_tab(&mut self, tab_id: String, port: Rc<T>, timeout_ms: u32, now: f64) {
+        if !self.tabs.contains(&tab_id) {
+            self.tabs.push_back(tab_id.clone());
+        }
+        self.liveness.insert(
+            tab_id.clone(),
+            Liveness {
+                last_seen: now,
+                timeout: timeout_ms as f64,
+            },
+        );
+        self.ports.insert(tab_id, port);
+    }
+
+    /// Fails if tabs here already elect another way than `election`.
+    fn check_election(&self, election: Election) -> Result<(), String> {
+        match self.election {
+            Some(mode) if mode != election => Err(format!(
+                "Tab elects by {:?} but the other tabs elect by {:?}; every tab of a database \
+                 must use the same election",
+                election, mode
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_tab(&mut self, tab_id: &str) {
+        log(&format!("Removing tab: {}", tab_id));
+        self.tabs.retain(|id| id != tab_id);
+        self.ports.remove(tab_id);
+        self.liveness.remove(tab_id);
+        if self.lock_holder.as_deref() == Some(tab_id) {
+            self.lock_holder = None;
+        }
+    }
+
+    /// Records a heartbeat. Returns false if the tab is not registered.
+    fn touch(&mut self, tab_id: &str, now: f64) -> bool {
+        match self.liveness.get_mut(tab_id) {
+            Some(liveness) => {
+                liveness.last_seen = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Tabs that have been silent for longer than their timeout.
+    fn expired(&self, now: f64) -> Vec<String> {
+        self.tabs
+            .iter()
+            .filter(|id| {
+                self.liveness
+                    .get(*id)
+                    .is_some_and(|l| now - l.last_seen > l.timeout)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn broadcast(&self, msg: &TabMessage<P>) {
+        for port in self.ports.values() {
+            let _ = port.post(msg);
+        }
+    }
+
+    /// Tells every tab about the new leader if it is no longer `old_leader`,
+    /// and hands it the requests the old leader never answered.
+    fn announce_leader(&mut self, old_leader: Option<String>) {
+        let new_leader = self.get_leader().cloned();
+        if old_leader != new_leader {
+            log(&format!("👑 Leader is now {:?}", new_leader));
+            if new_leader.is_none() {
+                // The old leader may still answer, e.g. busy after yielding
+                // the lock, but only the next one's answer counts
+                for entry in &mut self.in_flight {
+                    entry.leader = None;
+                }
+            }
+            self.broadcast(&TabMessage::LeaderChanged {
+                leader_id: new_leader,
+            });
+            self.dispatch();
+        }
+    }
+
+    /// Forwards every unanswered request that the current leader has not
+    /// seen yet.
+    ///
+    /// A leader that went away after running a write but before answering
+    /// leaves no trace of it here, so the write may run a second time.
+    fn dispatch(&mut self) {
+        let Some(leader_id) = self.get_leader().cloned() else {
+            return;
+        };
+        let Some(leader_port) = self.ports.get(&leader_id).cloned() else {
+            return;
+        };
+
+        for entry in &mut self.in_flight {
+            if entry.leader.as_ref() == Some(&leader_id) {
+                continue;
+            }
+            if entry.leader.is_some() {
+                log(&format!(
+                    "🔁 Replaying request {} of tab {} on {}",
+                    entry.request_id, entry.from_tab_id, leader_id
+                ));
+            }
+            let forward_id = self.next_forward_id;
+            self.next_forward_id = forward_id.wrapping_add(1);
+            let query = TabMessage::ExecuteQuery {
+                request: entry.request.clone(),
+                from_tab_id: entry.from_tab_id.clone(),
+                request_id: forward_id,
+            };
+            match leader_port.post(&query) {
+                Ok(_) => {
+                    entry.leader = Some(leader_id.clone());
+                    entry.forward_id = forward_id;
+                }
+                Err(e) => {
+                    error(&format!("❌ Failed to forward query: {:?}", e));
+                    // Whatever the last leader sends back is stale now
+                    entry.leader = None;
+                }
+            }
+        }
+    }
+
+    /// Stops tracking a request once it has been answered, returning the
+    /// id the requester knows it by. Returns `None` if the answer is not to
+    /// the latest forward or did not come from the tab it went to, e.g. a
+    /// former leader finishing after a handoff.
+    fn complete(&mut self, from_tab_id: &str, forward_id: u32, port: &Rc<T>) -> Option<u32> {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|e| e.from_tab_id == from_tab_id && e.forward_id == forward_id)?;
+        let leader_port = self.in_flight[index]
+            .leader
+            .as_ref()
+            .and_then(|id| self.ports.get(id));
+        if !leader_port.is_some_and(|p| Rc::ptr_eq(p, port)) {
+            return None;
+        }
+        Some(self.in_flight.remove(index).request_id)
+    }
+
+    /// Adds a tab and tells it who the leader is.
+    fn join_tab(&mut self, tab_id: String, port: Rc<T>, timeout_ms: u32, now: f64) {
+        let old_leader = self.get_leader().cloned();
+        self.register_tab(tab_id, port.clone(), timeout_ms, now);
+
+        if old_leader.as_ref() == self.get_leader() {
+            // Nobody else needs to hear about it, but the new tab does
+            let msg = TabMessage::LeaderChanged {
+                leader_id: old_leader,
+            };
+            send(&port, &msg);
+        } else {
+            self.announce_leader(old_leader);
+        }
+    }
+
+    /// Moves a tab to the front of the line, making it the leader. Under lock
+    /// election this is how the lock holder claims leadership.
+    fn promote(&mut self, tab_id: &str) {
+        let old_leader = self.get_leader().cloned();
+        if self.election == Some(Election::Lock) {
+            self.lock_holder = Some(tab_id.to_string());
+        }
+        if let Some(index) = self.tabs.iter().position(|id| id == tab_id) {
+            let tab_id = self.tabs.remove(index).unwrap();
+            self.tabs.push_front(tab_id);
+        }
+        self.announce_leader(old_leader);
+    }
+
+    /// Moves a tab to the back of the line, so the next one leads if it was
+    /// the leader.
+    fn demote(&mut self, tab_id: &str) {
+        let old_leader = self.get_leader().cloned();
+        if let Some(index) = self.tabs.iter().position(|id| id == tab_id) {
+            let tab_id = self.tabs.remove(index).unwrap();
+            self.tabs.push_back(tab_id);
+        }
+        self.announce_leader(old_leader);
+    }
+
+    /// Removes a tab that has gone away. If it was the leader, the next tab
+    /// takes over and every tab hears about it. The leader is asked to roll
+    /// back anything the tab left open.
+    fn drop_tab(&mut self, tab_id: &str) {
+        let old_leader = self.get_leader().cloned();
+        self.remove_tab(tab_id);
+        // Nobody is left to read the answers to its own requests
+        self.in_flight.retain(|e| e.from_tab_id != tab_id);
+        self.announce_leader(old_leader);
+
+        if let Some(leader_port) = self.get_leader().and_then(|id| self.ports.get(id)) {
+            let msg = TabMessage::TabLeft {
+                tab_id: tab_id.to_string(),
+            };
+            send(leader_port, &msg);
+        }
+    }
+}
+
+impl<P: Clone + Debug, T: Transport<P>> Default for TabState<P, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn send<P, T: Transport<P>>(port: &T, msg: &TabMessage<P>) {
+    if let Err(e) = port.post(msg) {
+        error(&format!("❌ Failed to send {:?}", e));
+    }
+}
@@ -0,0 +1,862 @@
+//! Runs many tabs, their shared worker and their SQLite workers in one
+//! process, in an order picked by a seeded scheduler, and checks what the
+//! coordinator protocol promises.
+//!
+//! The tabs are [`Tab`]s and the shared worker is a [`TabState`], or, when
+//! they coordinate over a `BroadcastChannel`, each tab has its own
+//! [`BroadcastCoordinator`]; the same code that runs in the browser. What
+//! differs is around them:
+//!
+//! - Messages wait in one queue per direction and tab, delivered first in,
+//!   first out like a `MessagePort`, but with the queues served in a random
+//!   order. Over a `BroadcastChannel` each tab has one queue for what the
+//!   others broadcast.
+//! - Under lock election the simulation hands out the lock the way Web Locks
+//!   do.
+//! - SQLite workers run each query whenever the scheduler gets to it,
+//!   against one native [`Database`] that only one worker can have open at
+//!   a time, like the file in OPFS.
+//! - Time only moves when nothing else can happen, jumping to the next
+//!   heartbeat, sweep or delayed message.
+//! - [`Faults`] drop and delay messages and crash tabs.
+//!
+//! Meanwhile tabs open, close, route queries and take over leadership. The
+//! same [`Config`] and seed always give the same run, so a failing seed
+//! can be replayed with its [`Report::trace`].
+
+use futures::executor::{block_on, LocalPool};
+use futures::task::LocalSpawnExt;
+use rng::Rng;
+use sqlite_wrapper::{Database, Error, OpenOptions, Params, ResultSet, Value, Vfs};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tab_coordinator::{BroadcastCoordinator, Election, Tab, TabMessage, Transport};
+use tab_coordinator_protocol::memory::Mailbox;
+use tab_coordinator_shared_worker::TabState;
+use worker::{SimWorker, Storage};
+
+mod rng;
+mod worker;
+
+/// How often the shared worker drops silent tabs, as in the browser.
+const SWEEP_INTERVAL_MS: f64 = 500.0;
+
+/// What routed requests and their replies carry in the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    /// SQL for the leader's worker to run.
+    Sql(String),
+    /// What came of running it.
+    Done(Result<ResultSet, Error>),
+    /// The request reached a tab that no longer leads.
+    Busy,
+}
+
+/// What goes wrong on the way, each as a chance per message or per step.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    /// Chance that a message is lost.
+    pub drop: f64,
+    /// Chance that a message is held back, along with everything behind it
+    /// in its queue.
+    pub delay: f64,
+    /// Longest a message is held back, in ms.
+    pub max_delay_ms: f64,
+    /// Chance, per step, that a tab other than the last one crashes.
+    pub crash: f64,
+}
+
+/// How the tabs reach each other, as `TabManager` picks in the browser.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Coordination {
+    /// Through the shared worker.
+    #[default]
+    SharedWorker,
+    /// Among themselves over a `BroadcastChannel`, where `SharedWorker` is
+    /// missing.
+    Broadcast,
+}
+
+/// The shape of a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub coordination: Coordination,
+    /// How the leader is chosen. Under [`Election::Lock`] the lock goes to
+    /// tabs in the order they asked, passes on as soon as its holder goes
+    /// and is stolen by a tab asking to lead.
+    pub election: Election,
+    /// Tabs open from the start.
+    pub tabs: usize,
+    /// Most tabs open at once.
+    pub max_tabs: usize,
+    /// Queries routed over the run.
+    pub queries: usize,
+    /// Scheduler steps with tabs coming and going and faults injected.
+    /// Afterwards the faults stop and the run goes on until everything
+    /// has settled.
+    pub steps: usize,
+    /// Chance, per step, that a tab does something: routes a query, opens
+    /// or closes, or asks to lead. Tabs stop once they have routed every
+    /// query.
+    pub activity: f64,
+    pub heartbeat_interval_ms: u32,
+    pub heartbeat_timeout_ms: u32,
+    pub faults: Faults,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            coordination: Coordination::default(),
+            election: Election::default(),
+            tabs: 3,
+            max_tabs: 5,
+            queries: 30,
+            steps: 500,
+            activity: 0.3,
+            heartbeat_interval_ms: 1000,
+            heartbeat_timeout_ms: 5000,
+            faults: Faults::default(),
+        }
+    }
+}
+
+/// A broken promise of the coordinator protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// Several open tabs took themselves for the leader, none of them with
+    /// a leader change on its way. Over a `BroadcastChannel`, a heartbeat or
+    /// goodbye from another tab may be one.
+    TwoLeaders { time: f64, tabs: Vec<String> },
+    /// A tab got more than one response to the same request.
+    DuplicateResponse { tab: String, request_id: u32 },
+    /// A query from a tab that is still open was never answered.
+    Unanswered { query: usize, tab: String },
+    /// A query was answered with something other than its result.
+    Failed {
+        query: usize,
+        tab: String,
+        reply: String,
+    },
+    /// A query was answered as done, but it is not in the database.
+    Lost { query: usize, tab: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TwoLeaders { time, tabs } => {
+                write!(f, "at {}ms tabs {:?} all lead", time, tabs)
+            }
+            Violation::DuplicateResponse { tab, request_id } => {
+                write!(f, "{} got request {} answered twice", tab, request_id)
+            }
+            Violation::Unanswered { query, tab } => {
+                write!(f, "query {} of {} was never answered", query, tab)
+            }
+            Violation::Failed { query, tab, reply } => {
+                write!(f, "query {} of {} failed: {}", query, tab, reply)
+            }
+            Violation::Lost { query, tab } => {
+                write!(f, "query {} of {} was answered but never ran", query, tab)
+            }
+        }
+    }
+}
+
+/// What a run found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub seed: u64,
+    pub violations: Vec<Violation>,
+    /// Queries that got their result.
+    pub answered: usize,
+    /// Queries that ran more than once, e.g. replayed after their leader
+    /// went away before answering. Allowed, but worth knowing about.
+    pub reruns: usize,
+    /// Everything that happened, in order.
+    pub trace: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Open,
+    Closed,
+    Crashed,
+}
+
+/// A message and when it may be delivered.
+struct Envelope {
+    ready_at: f64,
+    msg: TabMessage<Payload>,
+}
+
+type SimTab = Tab<Payload, Rc<Mailbox<Payload>>, SimWorker>;
+
+/// A tab's own coordinator, when tabs coordinate over a `BroadcastChannel`.
+struct Local {
+    coordinator: Rc<BroadcastCoordinator<Payload, Rc<Mailbox<Payload>>>>,
+    /// What the coordinator has for the tab.
+    inbox: Rc<Mailbox<Payload>>,
+}
+
+struct VirtualTab {
+    tab: SimTab,
+    worker: SimWorker,
+    status: Status,
+    /// Runs the tab's tasks. Dropped with the tab.
+    pool: Option<LocalPool>,
+    /// What the tab posts.
+    outbox: Rc<Mailbox<Payload>>,
+    /// The shared worker's port to the tab, or what the tab's own
+    /// coordinator broadcasts.
+    port: Rc<Mailbox<Payload>>,
+    local: Option<Local>,
+    /// Messages on their way to the shared worker.
+    up: VecDeque<Envelope>,
+    /// Messages on their way to the tab.
+    down: VecDeque<Envelope>,
+    next_heartbeat: f64,
+    /// Query responses delivered to the tab, by request id.
+    responses: HashMap<u32, u32>,
+}
+
+impl VirtualTab {
+    fn is_open(&self) -> bool {
+        self.status == Status::Open
+    }
+
+    /// Runs the tab's tasks until they all wait on something.
+    fn run(&mut self) {
+        if let Some(pool) = &mut self.pool {
+            pool.run_until_stalled();
+        }
+    }
+}
+
+type Reply = Rc<RefCell<Option<Result<Payload, Error>>>>;
+
+struct Query {
+    tab: usize,
+    reply: Reply,
+}
+
+/// Something the scheduler can do right now.
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    /// Hand the shared worker the next message from a tab.
+    Up(usize),
+    /// Hand a tab its next message from the shared worker or another tab.
+    Down(usize),
+    /// Let a tab's SQLite worker run its next query.
+    Job(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Route,
+    Open,
+    Close,
+    Lead,
+}
+
+/// One seeded run. See the [crate docs](crate).
+pub struct Simulation {
+    config: Config,
+    seed: u64,
+    rng: Rng,
+    now: f64,
+    next_sweep: f64,
+    /// Whether faults are still being injected.
+    faulty: bool,
+    coordinator: TabState<Payload, Mailbox<Payload>>,
+    /// Tabs queued for the lock under lock election, its holder first.
+    lock: VecDeque<usize>,
+    storage: Rc<Storage>,
+    tabs: Vec<VirtualTab>,
+    queries: Vec<Query>,
+    violations: Vec<Violation>,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    pub fn new(config: Config, seed: u64) -> Simulation {
+        // Named memory databases are shared by the whole process
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("simulation-{}", RUNS.fetch_add(1, Ordering::Relaxed));
+        let options = OpenOptions {
+            vfs: Vfs::Memory,
+            ..OpenOptions::default()
+        };
+        let db = block_on(Database::open(&name, &options)).expect("open simulation database");
+        db.execute("CREATE TABLE log (query INTEGER NOT NULL)", &Params::None)
+            .expect("create simulation table");
+
+        let mut simulation = Simulation {
+            config,
+            seed,
+            rng: Rng::new(seed),
+            now: 0.0,
+            next_sweep: SWEEP_INTERVAL_MS,
+            faulty: true,
+            coordinator: TabState::new(),
+            lock: VecDeque::new(),
+            storage: Rc::new(Storage::new(db)),
+            tabs: Vec::new(),
+            queries: Vec::new(),
+            violations: Vec::new(),
+            trace: Vec::new(),
+        };
+        for _ in 0..config.tabs {
+            simulation.open();
+        }
+        simulation
+    }
+
+    /// Runs the simulation to the end and checks what it left behind.
+    pub fn run(mut self) -> Report {
+        for _ in 0..self.config.steps {
+            if self.rng.chance(self.config.activity) {
+                self.act();
+            }
+            if self.rng.chance(self.config.faults.crash) {
+                self.crash_one();
+            }
+            self.step();
+        }
+
+        // Let delayed messages arrive and silent tabs time out
+        self.faulty = false;
+        self.trace("faults stop");
+        let settle_until = self.now
+            + 2.0 * self.config.heartbeat_timeout_ms as f64
+            + self.config.faults.max_delay_ms;
+        while self.now < settle_until || !self.is_idle() {
+            self.step();
+        }
+
+        self.finish()
+    }
+
+    fn trace(&mut self, event: impl fmt::Display) {
+        self.trace.push(format!("{:>8.0}ms {}", self.now, event));
+    }
+
+    fn open_tabs(&self) -> Vec<usize> {
+        (0..self.tabs.len())
+            .filter(|&index| self.tabs[index].is_open())
+            .collect()
+    }
+
+    /// Does one thing that can happen now, or moves time on if nothing
+    /// can.
+    fn step(&mut self) {
+        let events = self.ready_events();
+        if events.is_empty() {
+            self.advance();
+        } else {
+            let event = events[self.rng.below(events.len())];
+            self.dispatch(event);
+        }
+        self.collect();
+        self.check_leaders();
+    }
+
+    fn ready_events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (index, tab) in self.tabs.iter().enumerate() {
+            if tab.up.front().is_some_and(|e| e.ready_at <= self.now) {
+                events.push(Event::Up(index));
+            }
+            if !tab.is_open() {
+                continue;
+            }
+            if tab.down.front().is_some_and(|e| e.ready_at <= self.now) {
+                events.push(Event::Down(index));
+            }
+            if tab.worker.ready() {
+                events.push(Event::Job(index));
+            }
+        }
+        events
+    }
+
+    /// Whether nothing is left to deliver or run.
+    fn is_idle(&self) -> bool {
+        self.tabs.iter().all(|tab| {
+            tab.up.is_empty() && (!tab.is_open() || (tab.down.is_empty() && !tab.worker.ready()))
+        })
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::Up(index) => {
+                let envelope = self.tabs[index].up.pop_front().unwrap();
+                let tab_id = self.tabs[index].tab.tab_id().to_string();
+                self.trace(format_args!(
+                    "{} → shared worker: {:?}",
+                    tab_id, envelope.msg
+                ));
+                let port = self.tabs[index].port.clone();
+                self.coordinator.handle(envelope.msg, &port, self.now);
+            }
+            Event::Down(index) => {
+                let envelope = self.tabs[index].down.pop_front().unwrap();
+                let tab_id = self.tabs[index].tab.tab_id().to_string();
+                match &self.tabs[index].local {
+                    Some(local) => {
+                        let coordinator = local.coordinator.clone();
+                        self.trace(format_args!("channel → {}: {:?}", tab_id, envelope.msg));
+                        coordinator.receive(envelope.msg, self.now);
+                    }
+                    None => {
+                        self.trace(format_args!(
+                            "shared worker → {}: {:?}",
+                            tab_id, envelope.msg
+                        ));
+                        self.hand_over(index, envelope.msg);
+                    }
+                }
+            }
+            Event::Job(index) => {
+                let tab_id = self.tabs[index].tab.tab_id().to_string();
+                self.trace(format_args!("{}'s worker runs a query", tab_id));
+                self.tabs[index].worker.run_next();
+                self.tabs[index].run();
+            }
+        }
+    }
+
+    /// Has a tab handle a message from its coordinator.
+    fn hand_over(&mut self, index: usize, msg: TabMessage<Payload>) {
+        if let TabMessage::QueryResponse {
+            ref from_tab_id,
+            request_id,
+            ..
+        } = msg
+        {
+            if from_tab_id == self.tabs[index].tab.tab_id() {
+                self.count_response(index, request_id);
+            }
+        }
+        let leader_changed = matches!(msg, TabMessage::LeaderChanged { .. });
+        let tab = &mut self.tabs[index];
+        if let Some(task) = tab.tab.handle(msg) {
+            if let Some(pool) = &tab.pool {
+                pool.spawner().spawn_local(task).unwrap();
+            }
+        }
+        tab.run();
+
+        // Someone else's claim overtook ours, so the lock holder claims
+        // again, as `TabManager` does
+        let holds_lock = self.lock.front() == Some(&index);
+        if leader_changed && holds_lock && !self.tabs[index].tab.is_leader() {
+            let tab_id = self.tabs[index].tab.tab_id().to_string();
+            self.trace(format_args!(
+                "{} holds the lock and claims it again",
+                tab_id
+            ));
+            let _ = self.tabs[index].tab.request_leadership();
+        }
+    }
+
+    /// Passes what a tab posts to its own coordinator and what that has for
+    /// the tab back, until neither has anything left. In the browser they
+    /// call each other directly.
+    fn run_local(&mut self, index: usize) {
+        let Some(local) = &self.tabs[index].local else {
+            return;
+        };
+        let coordinator = local.coordinator.clone();
+        let inbox = local.inbox.clone();
+        let outbox = self.tabs[index].outbox.clone();
+        let tab_id = self.tabs[index].tab.tab_id().to_string();
+        while !outbox.is_empty() || !inbox.is_empty() {
+            for msg in outbox.drain() {
+                self.trace(format_args!("{} → its coordinator: {:?}", tab_id, msg));
+                let _ = coordinator.post(msg, self.now);
+            }
+            for msg in inbox.drain() {
+                self.trace(format_args!("its coordinator → {}: {:?}", tab_id, msg));
+                self.hand_over(index, msg);
+            }
+        }
+    }
+
+    fn count_response(&mut self, index: usize, request_id: u32) {
+        let tab = &mut self.tabs[index];
+        let count = tab.responses.entry(request_id).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            let tab = tab.tab.tab_id().to_string();
+            self.violations
+                .push(Violation::DuplicateResponse { tab, request_id });
+        }
+    }
+
+    /// Moves time to the next heartbeat, sweep or delayed message and fires
+    /// what is due.
+    fn advance(&mut self) {
+        let mut next = self.next_sweep;
+        for tab in &self.tabs {
+            if let Some(envelope) = tab.up.front() {
+                next = next.min(envelope.ready_at);
+            }
+            if tab.is_open() {
+                next = next.min(tab.next_heartbeat);
+                if let Some(envelope) = tab.down.front() {
+                    next = next.min(envelope.ready_at);
+                }
+            }
+        }
+        self.now = self.now.max(next);
+
+        let interval = self.config.heartbeat_interval_ms as f64;
+        let timeout = self.config.heartbeat_timeout_ms;
+        for tab in &mut self.tabs {
+            if tab.is_open() && tab.next_heartbeat <= self.now {
+                let _ = tab.tab.heartbeat(timeout);
+                tab.next_heartbeat += interval;
+            }
+        }
+        if self.next_sweep <= self.now {
+            self.coordinator.sweep(self.now);
+            self.next_sweep += SWEEP_INTERVAL_MS;
+        }
+    }
+
+    /// Moves what was posted into the queues, losing or holding back some
+    /// of it while faults are on.
+    fn collect(&mut self) {
+        for index in 0..self.tabs.len() {
+            self.run_local(index);
+            for msg in self.tabs[index].outbox.drain() {
+                self.enqueue(index, msg, true);
+            }
+            for msg in self.tabs[index].port.drain() {
+                match self.config.coordination {
+                    Coordination::SharedWorker => self.enqueue(index, msg, false),
+                    // Every other open tab gets its own copy
+                    Coordination::Broadcast => {
+                        for other in self.open_tabs() {
+                            if other != index {
+                                self.enqueue(other, msg.clone(), false);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn enqueue(&mut self, index: usize, msg: TabMessage<Payload>, up: bool) {
+        let faults = self.config.faults;
+        let tab_id = self.tabs[index].tab.tab_id().to_string();
+        if self.faulty && self.rng.chance(faults.drop) {
+            let route = if up { "from" } else { "to" };
+            self.trace(format_args!("lost {} {}: {:?}", route, tab_id, msg));
+            return;
+        }
+        let mut ready_at = self.now;
+        if self.faulty && self.rng.chance(faults.delay) {
+            ready_at += self.rng.unit() * faults.max_delay_ms;
+        }
+
+        let queue = match up {
+            true => &mut self.tabs[index].up,
+            false => &mut self.tabs[index].down,
+        };
+        // Nothing overtakes a held back message on the same port
+        if let Some(last) = queue.back() {
+            ready_at = ready_at.max(last.ready_at);
+        }
+        queue.push_back(Envelope { ready_at, msg });
+    }
+
+    /// Records every moment when more than one open tab acts as leader
+    /// without being told otherwise yet.
+    fn check_leaders(&mut self) {
+        let leaders: Vec<String> = self
+            .tabs
+            .iter()
+            .filter(|tab| tab.is_open() && tab.tab.is_leader())
+            .filter(|tab| !tab.down.iter().any(|e| self.changes_leader(&e.msg)))
+            .map(|tab| tab.tab.tab_id().to_string())
+            .collect();
+        if leaders.len() < 2 {
+            return;
+        }
+        // Once per stretch of the same tabs leading
+        let repeated = matches!(
+            self.violations.last(),
+            Some(Violation::TwoLeaders { tabs, .. }) if *tabs == leaders
+        );
+        if !repeated {
+            self.violations.push(Violation::TwoLeaders {
+                time: self.now,
+                tabs: leaders,
+            });
+        }
+    }
+
+    /// Whether a message on its way to a tab may change whom it takes for
+    /// the leader.
+    fn changes_leader(&self, msg: &TabMessage<Payload>) -> bool {
+        match msg {
+            TabMessage::LeaderChanged { .. } => true,
+            TabMessage::Heartbeat { .. } | TabMessage::Disconnect { .. } => {
+                self.config.coordination == Coordination::Broadcast
+            }
+            _ => false,
+        }
+    }
+
+    /// Has a random open tab do something.
+    fn act(&mut self) {
+        let open = self.open_tabs();
+        // Tabs settle down once every query has been sent
+        if open.is_empty() || self.queries.len() >= self.config.queries {
+            return;
+        }
+        let company = u32::from(open.len() > 1);
+        let actions = [
+            (Action::Route, 12),
+            (Action::Open, u32::from(open.len() < self.config.max_tabs)),
+            (Action::Close, company),
+            (Action::Lead, company),
+        ];
+        let Some(action) = self.rng.weighted(&actions) else {
+            return;
+        };
+        match action {
+            Action::Route => {
+                let index = open[self.rng.below(open.len())];
+                self.route(index);
+            }
+            Action::Open => self.open(),
+            Action::Close => {
+                let index = open[self.rng.below(open.len())];
+                self.close(index);
+            }
+            Action::Lead => {
+                let index = open[self.rng.below(open.len())];
+                let tab_id = self.tabs[index].tab.tab_id().to_string();
+                self.trace(format_args!("{} asks to lead", tab_id));
+                match self.config.election {
+                    Election::Coordinator => {
+                        let _ = self.tabs[index].tab.request_leadership();
+                    }
+                    Election::Lock => self.steal_lock(index),
+                }
+            }
+        }
+        self.collect();
+    }
+
+    fn open(&mut self) {
+        let tab_id = format!("tab-{}", self.tabs.len());
+        self.trace(format_args!("{} opens", tab_id));
+        let worker = SimWorker::new(tab_id.clone(), self.storage.clone());
+        let outbox = Rc::new(Mailbox::new());
+        let port = Rc::new(Mailbox::new());
+        let local = match self.config.coordination {
+            Coordination::SharedWorker => None,
+            Coordination::Broadcast => {
+                let coordinator = Rc::new(BroadcastCoordinator::new(port.clone(), &tab_id));
+                let inbox = Rc::new(Mailbox::new());
+                let handler_inbox = inbox.clone();
+                coordinator.set_handler(Rc::new(move |msg| {
+                    let _ = handler_inbox.post(&msg);
+                }));
+                Some(Local { coordinator, inbox })
+            }
+        };
+        let tab = Tab::new(tab_id, outbox.clone(), worker.clone());
+        tab.register(self.config.heartbeat_timeout_ms, self.config.election)
+            .unwrap();
+        self.tabs.push(VirtualTab {
+            tab,
+            worker,
+            status: Status::Open,
+            pool: Some(LocalPool::new()),
+            outbox,
+            port,
+            local,
+            up: VecDeque::new(),
+            down: VecDeque::new(),
+            next_heartbeat: self.now + self.config.heartbeat_interval_ms as f64,
+            responses: HashMap::new(),
+        });
+        self.collect();
+        if self.config.election == Election::Lock {
+            self.queue_for_lock(self.tabs.len() - 1);
+        }
+    }
+
+    /// Puts a tab at the back of the queue for the lock.
+    fn queue_for_lock(&mut self, index: usize) {
+        let holder = self.lock.front().copied();
+        self.lock.push_back(index);
+        self.claim_lock(holder);
+    }
+
+    /// Gives a tab the lock at once. The holder queues again behind the
+    /// others, as `TabManager` does when its lock is stolen.
+    fn steal_lock(&mut self, index: usize) {
+        let holder = self.lock.front().copied();
+        if holder == Some(index) {
+            return;
+        }
+        self.lock.retain(|&queued| queued != index);
+        if let Some(holder) = holder {
+            self.lock.pop_front();
+            self.lock.push_back(holder);
+        }
+        self.lock.push_front(index);
+        self.claim_lock(holder);
+    }
+
+    /// Takes a tab that went away out of the queue for the lock.
+    fn leave_lock(&mut self, index: usize) {
+        let holder = self.lock.front().copied();
+        self.lock.retain(|&queued| queued != index);
+        self.claim_lock(holder);
+    }
+
+    /// Has a new holder of the lock claim leadership, as `TabManager` does
+    /// when it is granted the lock.
+    fn claim_lock(&mut self, previous: Option<usize>) {
+        let Some(&holder) = self.lock.front() else {
+            return;
+        };
+        if Some(holder) == previous {
+            return;
+        }
+        let tab_id = self.tabs[holder].tab.tab_id().to_string();
+        self.trace(format_args!("{} gets the lock", tab_id));
+        let _ = self.tabs[holder].tab.request_leadership();
+        self.collect();
+    }
+
+    /// Starts a query that records itself in the database.
+    fn route(&mut self, index: usize) {
+        let query = self.queries.len();
+        let reply: Reply = Rc::new(RefCell::new(None));
+        self.queries.push(Query {
+            tab: index,
+            reply: reply.clone(),
+        });
+
+        let tab = &mut self.tabs[index];
+        let sql = format!("INSERT INTO log (query) VALUES ({})", query);
+        let routed = tab.tab.route(Payload::Sql(sql));
+        if let Some(pool) = &tab.pool {
+            pool.spawner()
+                .spawn_local(async move {
+                    *reply.borrow_mut() = Some(routed.await);
+                })
+                .unwrap();
+        }
+        tab.run();
+        let tab_id = tab.tab.tab_id().to_string();
+        self.trace(format_args!("{} routes query {}", tab_id, query));
+    }
+
+    /// Closes a tab the way a page unload does: it says goodbye, and what
+    /// it already posted still arrives.
+    fn close(&mut self, index: usize) {
+        let _ = self.tabs[index].tab.disconnect();
+        self.collect();
+        self.shut_down(index, Status::Closed);
+    }
+
+    fn crash_one(&mut self) {
+        let open = self.open_tabs();
+        if open.len() < 2 {
+            return;
+        }
+        let index = open[self.rng.below(open.len())];
+        self.tabs[index].up.clear();
+        self.shut_down(index, Status::Crashed);
+    }
+
+    fn shut_down(&mut self, index: usize, status: Status) {
+        let tab = &mut self.tabs[index];
+        tab.status = status;
+        tab.pool = None;
+        tab.worker.stop();
+        tab.outbox.close();
+        tab.port.close();
+        tab.down.clear();
+        let tab_id = tab.tab.tab_id().to_string();
+        let verb = match status {
+            Status::Crashed => "crashes",
+            _ => "closes",
+        };
+        self.trace(format_args!("{} {}", tab_id, verb));
+        // The browser lets go of its lock
+        self.leave_lock(index);
+    }
+
+    /// Checks the queries once everything has settled.
+    fn finish(mut self) -> Report {
+        let logged = self
+            .storage
+            .db
+            .query(
+                "SELECT query, count(*) FROM log GROUP BY query",
+                &Params::None,
+            )
+            .expect("read simulation log");
+        let mut runs = HashMap::new();
+        for row in &logged.rows {
+            if let [Value::Integer(query), Value::Integer(count)] = row.as_slice() {
+                runs.insert(*query as usize, *count);
+            }
+        }
+
+        let mut answered = 0;
+        for (id, query) in self.queries.iter().enumerate() {
+            let tab = &self.tabs[query.tab];
+            let tab_id = tab.tab.tab_id().to_string();
+            let violation = match &*query.reply.borrow() {
+                None if tab.is_open() => Some(Violation::Unanswered {
+                    query: id,
+                    tab: tab_id,
+                }),
+                None => None,
+                Some(Ok(Payload::Done(Ok(_)))) => {
+                    answered += 1;
+                    (!runs.contains_key(&id)).then_some(Violation::Lost {
+                        query: id,
+                        tab: tab_id,
+                    })
+                }
+                Some(reply) => Some(Violation::Failed {
+                    query: id,
+                    tab: tab_id,
+                    reply: format!("{:?}", reply),
+                }),
+            };
+            self.violations.extend(violation);
+        }
+
+        Report {
+            seed: self.seed,
+            violations: self.violations,
+            answered,
+            reruns: runs.values().filter(|&&count| count > 1).count(),
+            trace: self.trace,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::Payload;
+use futures::channel::oneshot;
+use futures::future::{self, LocalBoxFuture};
+use sqlite_wrapper::{ffi, Database, Error, Params};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use tab_coordinator::Executor;
+
+/// The database all virtual workers share, standing in for the file in
+/// OPFS.
+pub(crate) struct Storage {
+    pub(crate) db: Database,
+    /// The worker that has the database open. Like the OPFS access handles,
+    /// only one worker can have it, and the others wait until it lets go.
+    holder: RefCell<Option<String>>,
+}
+
+impl Storage {
+    pub(crate) fn new(db: Database) -> Storage {
+        Storage {
+            db,
+            holder: RefCell::new(None),
+        }
+    }
+}
+
+struct Job {
+    sql: String,
+    reply: oneshot::Sender<Payload>,
+}
+
+/// A tab's virtual SQLite worker. Requests queue up until the simulation
+/// decides to run them, so anything can happen while they wait.
+#[derive(Clone)]
+pub(crate) struct SimWorker {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    tab_id: String,
+    storage: Rc<Storage>,
+    jobs: RefCell<VecDeque<Job>>,
+}
+
+impl SimWorker {
+    pub(crate) fn new(tab_id: String, storage: Rc<Storage>) -> SimWorker {
+        SimWorker {
+            inner: Rc::new(Inner {
+                tab_id,
+                storage,
+                jobs: RefCell::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    /// Whether there is a job to run and nobody else has the database.
+    pub(crate) fn ready(&self) -> bool {
+        let holder = self.inner.storage.holder.borrow();
+        !self.inner.jobs.borrow().is_empty()
+            && holder.as_ref().is_none_or(|id| *id == self.inner.tab_id)
+    }
+
+    /// Runs the oldest job, opening the database first if needed.
+    pub(crate) fn run_next(&self) {
+        let Some(job) = self.inner.jobs.borrow_mut().pop_front() else {
+            return;
+        };
+        *self.inner.storage.holder.borrow_mut() = Some(self.inner.tab_id.clone());
+        let result = self.inner.storage.db.execute(&job.sql, &Params::None);
+        let _ = job.reply.send(Payload::Done(result));
+    }
+
+    /// Drops every job and closes the database, as terminating the worker
+    /// does.
+    pub(crate) fn stop(&self) {
+        self.inner.jobs.borrow_mut().clear();
+        let mut holder = self.inner.storage.holder.borrow_mut();
+        if holder.as_ref() == Some(&self.inner.tab_id) {
+            *holder = None;
+        }
+    }
+}
+
+impl Executor<Payload> for SimWorker {
+    fn execute(&self, request: Payload) -> LocalBoxFuture<'static, Payload> {
+        let Payload::Sql(sql) = request else {
+            let error = Error::new(ffi::SQLITE_MISUSE, "Expected SQL to run");
+            return Box::pin(future::ready(Payload::Done(Err(error))));
+        };
+        let (reply, receiver) = oneshot::channel();
+        self.inner.jobs.borrow_mut().push_back(Job { sql, reply });
+        Box::pin(async move {
+            // Jobs are dropped when the worker is restarted
+            receiver.await.unwrap_or_else(|_| {
+                let error = Error::new(ffi::SQLITE_ABORT, "SQLite worker restarted");
+                Payload::Done(Err(error))
+            })
+        })
+    }
+
+    fn busy(&self) -> Payload {
+        Payload::Busy
+    }
+
+    fn release(&self) {
+        self.stop();
+    }
+
+    fn abandon(&self, _tab_id: &str) {
+        // Each query is a statement of its own, so nothing is ever left open
+    }
+}
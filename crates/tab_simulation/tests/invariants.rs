@@ -0,0 +1,132 @@
+//! The coordinator protocol under many seeded interleavings.
+
+use tab_coordinator::Election;
+use tab_simulation::{Config, Coordination, Faults, Report, Simulation, Violation};
+
+const SEEDS: u64 = 100;
+
+fn run(config: Config, seed: u64) -> Report {
+    Simulation::new(config, seed).run()
+}
+
+/// Fails with the first seed that broke something, and how to replay it.
+fn assert_holds(config: Config) {
+    let mut answered = 0;
+    for seed in 0..SEEDS {
+        let report = run(config, seed);
+        if !report.is_ok() {
+            let violations: Vec<String> = report.violations.iter().map(|v| v.to_string()).collect();
+            panic!(
+                "seed {} broke the protocol:\n{}\n\ntrace:\n{}",
+                seed,
+                violations.join("\n"),
+                report.trace.join("\n")
+            );
+        }
+        answered += report.answered;
+    }
+    assert!(answered > 0);
+}
+
+#[test]
+fn same_seed_replays_the_same_run() {
+    let config = Config {
+        faults: Faults {
+            drop: 0.01,
+            delay: 0.2,
+            max_delay_ms: 3000.0,
+            crash: 0.005,
+        },
+        ..Config::default()
+    };
+
+    let first = run(config, 7);
+    let second = run(config, 7);
+    assert_eq!(first.trace, second.trace);
+    assert_eq!(first.violations, second.violations);
+    assert_ne!(first.trace, run(config, 8).trace);
+}
+
+#[test]
+fn queries_survive_tabs_coming_and_going() {
+    assert_holds(Config::default());
+}
+
+#[test]
+fn queries_survive_delays_and_crashes() {
+    // Delays stay below the heartbeat timeout, so only dead tabs are dropped
+    assert_holds(Config {
+        faults: Faults {
+            drop: 0.0,
+            delay: 0.2,
+            max_delay_ms: 3000.0,
+            crash: 0.005,
+        },
+        ..Config::default()
+    });
+}
+
+#[test]
+fn queries_survive_lock_election() {
+    assert_holds(Config {
+        election: Election::Lock,
+        faults: Faults {
+            drop: 0.0,
+            delay: 0.2,
+            max_delay_ms: 3000.0,
+            crash: 0.005,
+        },
+        ..Config::default()
+    });
+}
+
+#[test]
+fn queries_survive_coordinating_over_broadcast_channel() {
+    // A newcomer waits a heartbeat interval for the others to answer before
+    // it elects, so messages between tabs must arrive within one
+    assert_holds(Config {
+        coordination: Coordination::Broadcast,
+        faults: Faults {
+            drop: 0.0,
+            delay: 0.2,
+            max_delay_ms: 500.0,
+            crash: 0.005,
+        },
+        ..Config::default()
+    });
+}
+
+#[test]
+fn queries_survive_lock_election_over_broadcast_channel() {
+    assert_holds(Config {
+        coordination: Coordination::Broadcast,
+        election: Election::Lock,
+        faults: Faults {
+            drop: 0.0,
+            delay: 0.2,
+            max_delay_ms: 500.0,
+            crash: 0.005,
+        },
+        ..Config::default()
+    });
+}
+
+#[test]
+fn lost_messages_are_caught() {
+    // Ports never lose messages, so nothing in the protocol resends them
+    let config = Config {
+        faults: Faults {
+            drop: 0.05,
+            ..Faults::default()
+        },
+        ..Config::default()
+    };
+
+    let caught = (0..SEEDS).any(|seed| {
+        run(config, seed)
+            .violations
+            .iter()
+            .any(|v| matches!(v, Violation::Unanswered { .. }))
+    });
+    assert!(caught);
+}
@@ -13,11 +13,21 @@ self.onconnect = (e) => {
     }
 };
 
+// Hold on to requests posted before WASM is ready, such as the
+// configuration BrowserSQLite sends right after creating the worker
+const pendingMessages = [];
+self.onmessage = (e) => pendingMessages.push(e.data);
+
 // Initialize the worker
 wasm_bindgen("./sqlite_wrapper_bg.wasm").then(async () => {
     try {
         await wasm_bindgen.main();
         console.log("SQLite worker initialized");
+
+        // main() installed the real handler; replay what arrived before it
+        for (const data of pendingMessages.splice(0)) {
+            self.dispatchEvent(new MessageEvent('message', { data }));
+        }
         
         // Store the real handler
         realOnconnect = wasm_bindgen.handle_connect;